@@ -0,0 +1,217 @@
+//! Absorb mode: instead of splitting a commit into new ones, turn its hunks
+//! into `fixup!` commits for the earlier commits that last touched the same
+//! lines, then squash them in with an autosquash rebase.
+
+use crate::backend::{self, ApplyTo, CommitOptions};
+use crate::diff::{file_name, parse_diff, HunkHeader};
+use crate::save;
+use crate::ui::{self, CommitId, CommitInfo, UiState};
+use crate::{get_output, git, git_path, rev_parse};
+use anyhow::Result;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+
+/// The old-side line numbers a hunk changes, or its context lines for a
+/// pure addition.
+fn blamed_lines(hunk: &str) -> Vec<usize> {
+    let Some(range) = HunkHeader::parse(hunk) else {
+        return Vec::new();
+    };
+    let mut old = range.old_start;
+    let (mut removed, mut context) = (Vec::new(), Vec::new());
+    for line in hunk.split('\n').skip(1) {
+        match line.chars().next() {
+            Some('+') | Some('\\') => {}
+            Some('-') => {
+                removed.push(old);
+                old += 1;
+            }
+            _ => {
+                context.push(old);
+                old += 1;
+            }
+        }
+    }
+    if removed.is_empty() {
+        context
+    } else {
+        removed
+    }
+}
+
+/// The commit that last touched most of `lines` of `path` in `rev`.
+/// `candidates` are newest first; ties go to the newest commit.
+fn blame_target(rev: &str, path: &str, lines: &[usize], candidates: &[String]) -> Option<String> {
+    let (first, last) = (lines.iter().min()?, lines.iter().max()?);
+    let blame = get_output(git().args([
+        "blame",
+        "--porcelain",
+        "-L",
+        &format!("{first},{last}"),
+        rev,
+        "--",
+        path,
+    ]))
+    .ok()?;
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for line in blame.split('\n') {
+        // "<sha> <original line> <final line> [<group size>]"
+        let mut parts = line.split(' ');
+        let (Some(sha), Some(_), Some(final_line)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if sha.len() == 40 && final_line.parse().is_ok_and(|n: usize| lines.contains(&n)) {
+            *counts.entry(sha).or_default() += 1;
+        }
+    }
+    candidates
+        .iter()
+        .filter_map(|sha| Some((sha, *counts.get(sha.as_str())?)))
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(sha, _)| sha.clone())
+}
+
+/// Absorbs `commit` into the earlier, unpushed commits of the branch.
+pub fn absorb(
+    commit: &str,
+    diff_context_size: usize,
+    reset_author: bool,
+    commit_options: &CommitOptions,
+) -> Result<()> {
+    anyhow::ensure!(
+        git().args(["diff", "--quiet", "HEAD"]).status()?.success(),
+        "Absorbing needs a clean working tree"
+    );
+    anyhow::ensure!(
+        git()
+            .args(["merge-base", "--is-ancestor", commit, "HEAD"])
+            .status()?
+            .success(),
+        "{commit} is not an ancestor of HEAD"
+    );
+    let parent = rev_parse(format!("{commit}~"))?;
+    let raw_hunks = backend::open(diff_context_size, ApplyTo::WorkTree, &Default::default())
+        .diff(&parent, Some(commit))?;
+    let original_commit_message =
+        get_output(git().args(["log", "-1", "--pretty=format:%B", commit]))?;
+    let files = parse_diff(&raw_hunks)?;
+    // Unpushed commits the hunks may go to, newest first.
+    let candidates = get_output(git().args(["rev-list", &parent, "--not", "--remotes"]))?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect_vec();
+
+    let mut ui_state = UiState {
+        files,
+        source_commit: Some(commit.to_string()),
+        commit_options: commit_options.clone(),
+        ..Default::default()
+    };
+    ui_state.insert_message(
+        '0',
+        CommitInfo {
+            commit_message: original_commit_message,
+        },
+    );
+    let mut targets: BTreeMap<String, CommitId> = BTreeMap::new();
+    let mut free_ids = '1'..='9';
+    let hunk_targets = ui_state
+        .hunks()
+        .map(|(_, (header, hunk))| {
+            blame_target(&parent, file_name(header), &blamed_lines(hunk), &candidates)
+        })
+        .collect_vec();
+    for target in hunk_targets {
+        let id = match target {
+            Some(sha) => match targets.get(&sha) {
+                Some(id) => Some(*id),
+                None => free_ids.next().inspect(|&id| {
+                    let subject = get_output(git().args(["log", "-1", "--pretty=format:%s", &sha]))
+                        .unwrap_or_else(|_| sha.clone());
+                    ui_state.insert_message(
+                        id,
+                        CommitInfo {
+                            commit_message: format!("fixup! {subject}"),
+                        },
+                    );
+                    targets.insert(sha, id);
+                }),
+            },
+            None => None,
+        };
+        ui_state.hunk_commits.push(Some(id.unwrap_or('0')));
+    }
+    let oldest_target = candidates
+        .iter()
+        .rev()
+        .find(|sha| targets.contains_key(*sha))
+        .cloned();
+
+    let branch = get_output(git().args(["symbolic-ref", "--quiet", "--short", "HEAD"]))
+        .ok()
+        .map(|branch| branch.trim().to_string());
+    let original = save::Original::read(commit, reset_author)?;
+    let commit = rev_parse(commit)?;
+    let ui_state = ui::run(ui_state, move |plan, commit_options, progress| {
+        // Replace `commit` with the fixups, replay what came after it, then
+        // squash the fixups into their targets.
+        let head = rev_parse("HEAD")?;
+        get_output(git().args(["checkout", "-q", "--detach", &parent]))?;
+        let replace = || -> Result<()> {
+            save::save(
+                &plan,
+                &*backend::open(diff_context_size, ApplyTo::WorkTree, commit_options),
+                &original,
+                progress,
+            )?;
+            if head != commit {
+                progress.report(format!("Replaying {commit}..{head}"));
+                get_output(git().args(["cherry-pick", &format!("{commit}..{head}")]))?;
+            }
+            Ok(())
+        };
+        let original_head = branch.as_deref().unwrap_or(&head);
+        if let Err(err) = replace() {
+            // Nothing points at the new commits yet, so going back to where
+            // HEAD was undoes them.
+            if git_path("CHERRY_PICK_HEAD")?.exists() {
+                get_output(git().args(["cherry-pick", "--abort"]))?;
+            }
+            get_output(git().args(["checkout", "-q", "-f", original_head]))?;
+            return Err(err.context(format!(
+                "Nothing was absorbed; HEAD is back on {original_head}"
+            )));
+        }
+        if let Some(branch) = &branch {
+            get_output(git().args(["checkout", "-q", "-B", branch]))?;
+        }
+        if let Some(oldest_target) = &oldest_target {
+            progress.report("Squashing the fixups");
+            let mut rebase = git();
+            rebase
+                .args(["rebase", "-i", "--autosquash"])
+                .env(crate::GIT_SEQUENCE_EDITOR, "true");
+            match rev_parse(format!("{oldest_target}~")) {
+                Ok(base) => rebase.arg(base),
+                Err(_) => rebase.arg("--root"),
+            };
+            if let Err(err) = get_output(&mut rebase) {
+                if git_path("rebase-merge")?.exists() {
+                    get_output(git().args(["rebase", "--abort"]))?;
+                }
+                get_output(git().args(["reset", "-q", "--hard", &head]))?;
+                return Err(err.context(format!(
+                    "The fixups don't squash cleanly into their commits; \
+                     nothing was absorbed and HEAD is back on {original_head}"
+                )));
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(err) = ui_state.save_error {
+        anyhow::bail!("Failed to absorb the commit:\n{err}");
+    }
+    Ok(())
+}
@@ -0,0 +1,426 @@
+//! How diffs are read and commits are written: by running `git`, or in
+//! process with libgit2 when built with the `libgit2` feature.
+
+use crate::save::Author;
+use crate::{get_output, get_output_with_input, git, git_path};
+use anyhow::Result;
+use std::io::Write as _;
+use std::process::Command;
+
+/// Where the save step applies each commit's patches.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApplyTo {
+    /// The working tree, staging what changed. The working tree starts out
+    /// without any of the changes.
+    WorkTree,
+    /// Only the index, for changes that are already in the working tree.
+    Index,
+}
+
+/// Whether and how to sign the new commits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Sign {
+    /// As `commit.gpgSign` says.
+    #[default]
+    Default,
+    /// With the given key, or the default one. `gpg.format` picks GPG, SSH or
+    /// X.509.
+    Key(Option<String>),
+    Off,
+}
+
+/// How `git commit` is run for the new commits.
+#[derive(Clone, Debug, Default)]
+pub struct CommitOptions {
+    pub sign: Sign,
+    /// Skip the `pre-commit` and `commit-msg` hooks.
+    pub no_verify: bool,
+}
+
+impl CommitOptions {
+    /// The signing arguments of `git commit` and `git commit-tree`.
+    pub fn sign_args(&self) -> Vec<String> {
+        match &self.sign {
+            Sign::Default => vec![],
+            Sign::Key(None) => vec!["-S".to_string()],
+            Sign::Key(Some(key)) => vec![format!("-S{key}")],
+            Sign::Off => vec!["--no-gpg-sign".to_string()],
+        }
+    }
+
+    /// These options with `Sign::Default` replaced by what `commit.gpgSign`
+    /// and `user.signingKey` say, for `git commit-tree`, which ignores them.
+    pub fn with_configured_signing(&self) -> Self {
+        let config = |key: &str| {
+            get_output(git().args(["config", key]))
+                .ok()
+                .map(|value| value.trim().to_string())
+        };
+        let gpg_sign = get_output(git().args(["config", "--type=bool", "commit.gpgSign"]))
+            .is_ok_and(|value| value.trim() == "true");
+        match self.sign {
+            Sign::Default if gpg_sign => Self {
+                sign: Sign::Key(config("user.signingKey")),
+                ..self.clone()
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+pub trait Backend {
+    fn name(&self) -> &'static str;
+
+    /// The patch from `from` to `to`, or to the working tree if `to` is
+    /// `None`, in `git diff -p` format.
+    fn diff(&self, from: &str, to: Option<&str>) -> Result<String>;
+
+    /// Creates a commit on top of HEAD from `patches`, each the patch of one
+    /// file.
+    /// `author` defaults to the current user, now.
+    fn commit(&self, patches: &[String], message: &str, author: Option<&Author>) -> Result<()>;
+
+    /// Creates a commit from `patches` on top of `parent`, without touching
+    /// HEAD, the index or the working tree, and without running hooks.
+    /// `Sign::Default` doesn't sign here; see
+    /// `CommitOptions::with_configured_signing`. Returns the new commit's id.
+    fn commit_tree(
+        &self,
+        parent: &str,
+        patches: &[String],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<String>;
+
+    /// Brings the index (and the working tree, for `ApplyTo::WorkTree`) in
+    /// line with HEAD once all commits are written.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Moves HEAD back to `commit`, with the index (and the working tree,
+    /// for `ApplyTo::WorkTree`), dropping the commits written since.
+    fn reset(&self, commit: &str) -> Result<()>;
+}
+
+/// Runs `git` for every step.
+pub struct Subprocess {
+    pub diff_context_size: usize,
+    pub apply_to: ApplyTo,
+    pub options: CommitOptions,
+}
+
+impl Subprocess {
+    fn apply_patch(&self, patch: &str) -> Result<()> {
+        log::debug!("PATCH: {patch:?}");
+        let mut cmd = git();
+        match self.apply_to {
+            ApplyTo::Index => cmd.args(["apply", "--cached"]),
+            // Stages the patched files too, new ones included.
+            ApplyTo::WorkTree => cmd.args(["apply", "--index", "--reject"]),
+        };
+        if self.diff_context_size == 0 {
+            cmd.arg("--unidiff-zero");
+        }
+        get_output_with_input(&mut cmd, |stdin| {
+            stdin.write_all(patch.as_bytes())?;
+            stdin.flush()?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+impl Backend for Subprocess {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn diff(&self, from: &str, to: Option<&str>) -> Result<String> {
+        let mut cmd = git();
+        cmd.args(["diff", "-p", &format!("-U{}", self.diff_context_size), from]);
+        cmd.args(to);
+        get_output(&mut cmd)
+    }
+
+    fn commit(&self, patches: &[String], message: &str, author: Option<&Author>) -> Result<()> {
+        for patch in patches {
+            self.apply_patch(patch)?;
+        }
+        let mut cmd = git();
+        cmd.args(["commit", "-m", message]);
+        cmd.args(self.options.sign_args());
+        if self.options.no_verify {
+            cmd.arg("--no-verify");
+        }
+        set_author(&mut cmd, author);
+        // Show what a hook or the signing program said, without the command
+        // line around it.
+        let output = cmd.output()?;
+        if !output.status.success() {
+            let mut report = crate::vec_to_utf8(output.stdout);
+            report.push_str(&crate::vec_to_utf8(output.stderr));
+            anyhow::bail!("git commit failed:\n{}", report.trim_end());
+        }
+        Ok(())
+    }
+
+    fn commit_tree(
+        &self,
+        parent: &str,
+        patches: &[String],
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<String> {
+        // A private index, so the user's stays as it is.
+        let index = git_path("kgit-split-index")?;
+        let with_index = |cmd: &mut Command| {
+            cmd.env("GIT_INDEX_FILE", &index);
+        };
+        let mut read_tree = git();
+        with_index(read_tree.args(["read-tree", parent]));
+        get_output(&mut read_tree)?;
+        let result = (|| {
+            for patch in patches {
+                let mut apply = git();
+                with_index(apply.args(["apply", "--cached"]));
+                if self.diff_context_size == 0 {
+                    apply.arg("--unidiff-zero");
+                }
+                get_output_with_input(&mut apply, |stdin| {
+                    stdin.write_all(patch.as_bytes())?;
+                    Ok(())
+                })?;
+            }
+            let mut write_tree = git();
+            with_index(write_tree.arg("write-tree"));
+            let tree = get_output(&mut write_tree)?;
+            let mut commit_tree = git();
+            commit_tree
+                .args(["commit-tree", tree.trim(), "-p", parent, "-m", message])
+                .args(self.options.sign_args());
+            set_author(&mut commit_tree, author);
+            Ok(get_output(&mut commit_tree)?.trim().to_string())
+        })();
+        let _ = std::fs::remove_file(&index);
+        result
+    }
+
+    fn reset(&self, commit: &str) -> Result<()> {
+        let mode = match self.apply_to {
+            ApplyTo::WorkTree => "--hard",
+            ApplyTo::Index => "--mixed",
+        };
+        get_output(git().args(["reset", "-q", mode, commit]))?;
+        Ok(())
+    }
+}
+
+/// Makes `git commit` or `git commit-tree` use `author`, if given.
+pub fn set_author(cmd: &mut Command, author: Option<&Author>) {
+    if let Some(author) = author {
+        cmd.env("GIT_AUTHOR_NAME", &author.name)
+            .env("GIT_AUTHOR_EMAIL", &author.email)
+            .env("GIT_AUTHOR_DATE", &author.date);
+    }
+}
+
+#[cfg(feature = "libgit2")]
+pub use in_process::InProcess;
+
+#[cfg(feature = "libgit2")]
+mod in_process {
+    use super::{ApplyTo, Author, Backend, CommitOptions, Sign};
+    use anyhow::{Context, Result};
+    use git2::{
+        build::CheckoutBuilder, Commit, Diff, DiffFindOptions, DiffFormat, DiffOptions, Oid,
+        Repository, ResetType, Signature, Time,
+    };
+
+    /// Reads and writes objects directly with libgit2. Unlike `git commit`,
+    /// this doesn't run hooks or sign commits.
+    pub struct InProcess {
+        pub repo: Repository,
+        pub diff_context_size: usize,
+        pub apply_to: ApplyTo,
+    }
+
+    impl InProcess {
+        pub fn open(diff_context_size: usize, apply_to: ApplyTo) -> Result<Self> {
+            Ok(Self {
+                repo: Repository::open_from_env()?,
+                diff_context_size,
+                apply_to,
+            })
+        }
+
+        /// Whether `git commit` would do something this backend can't.
+        pub fn needs_git(&self, options: &CommitOptions) -> bool {
+            let config = self.repo.config().ok();
+            let gpg_sign = match options.sign {
+                Sign::Default => config
+                    .as_ref()
+                    .and_then(|config| config.get_bool("commit.gpgSign").ok())
+                    .unwrap_or(false),
+                Sign::Key(_) => true,
+                Sign::Off => false,
+            };
+            let hooks = config
+                .as_ref()
+                .and_then(|config| config.get_path("core.hooksPath").ok())
+                .unwrap_or_else(|| self.repo.path().join("hooks"));
+            let verify_hooks: &[&str] = match options.no_verify {
+                true => &[],
+                false => &["pre-commit", "commit-msg"],
+            };
+            gpg_sign
+                || ["prepare-commit-msg", "post-commit"]
+                    .iter()
+                    .chain(verify_hooks)
+                    .any(|hook| hooks.join(hook).exists())
+        }
+
+        /// Commits `patches` applied to `parent`'s tree, moving `update_ref`
+        /// to the new commit if given.
+        fn write_commit(
+            &self,
+            update_ref: Option<&str>,
+            parent: &Commit,
+            patches: &[String],
+            message: &str,
+            author: Option<&Author>,
+        ) -> Result<Oid> {
+            let diff = Diff::from_buffer(patches.concat().as_bytes())?;
+            let mut index = self
+                .repo
+                .apply_to_tree(&parent.tree()?, &diff, None)
+                .context("Failed to apply the patches")?;
+            let tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?;
+            let committer = self.repo.signature()?;
+            let author = match author {
+                Some(Author { name, email, date }) => {
+                    let (seconds, offset) = date
+                        .split_once(' ')
+                        .with_context(|| format!("Bad author date {date:?}"))?;
+                    let offset: i32 = offset.parse()?;
+                    let minutes = offset.signum() * (offset.abs() / 100 * 60 + offset.abs() % 100);
+                    Signature::new(name, email, &Time::new(seconds.parse()?, minutes))?
+                }
+                None => committer.clone(),
+            };
+            Ok(self
+                .repo
+                .commit(update_ref, &author, &committer, message, &tree, &[parent])?)
+        }
+    }
+
+    impl Backend for InProcess {
+        fn name(&self) -> &'static str {
+            "libgit2"
+        }
+
+        fn diff(&self, from: &str, to: Option<&str>) -> Result<String> {
+            let tree =
+                |rev: &str| -> Result<_> { Ok(self.repo.revparse_single(rev)?.peel_to_tree()?) };
+            let mut options = DiffOptions::new();
+            options.context_lines(self.diff_context_size as u32);
+            let mut diff = match to {
+                Some(to) => self.repo.diff_tree_to_tree(
+                    Some(&tree(from)?),
+                    Some(&tree(to)?),
+                    Some(&mut options),
+                )?,
+                None => self
+                    .repo
+                    .diff_tree_to_workdir_with_index(Some(&tree(from)?), Some(&mut options))?,
+            };
+            // Detect renames like `git diff` does, going by `diff.renames`.
+            let renames = self.repo.config()?.get_string("diff.renames");
+            let (renames, copies) = match renames.as_deref().unwrap_or("true") {
+                "false" | "no" | "off" | "0" => (false, false),
+                "copies" | "copy" => (true, true),
+                _ => (true, false),
+            };
+            if renames {
+                diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(copies)))?;
+            }
+            let mut patch = Vec::new();
+            diff.print(DiffFormat::Patch, |_, _, line| {
+                if let origin @ ('+' | '-' | ' ') = line.origin() {
+                    patch.push(origin as u8);
+                }
+                patch.extend_from_slice(line.content());
+                true
+            })?;
+            Ok(crate::vec_to_utf8(patch))
+        }
+
+        fn commit(&self, patches: &[String], message: &str, author: Option<&Author>) -> Result<()> {
+            let head = self.repo.head()?.peel_to_commit()?;
+            self.write_commit(Some("HEAD"), &head, patches, message, author)?;
+            Ok(())
+        }
+
+        fn commit_tree(
+            &self,
+            parent: &str,
+            patches: &[String],
+            message: &str,
+            author: Option<&Author>,
+        ) -> Result<String> {
+            let parent = self.repo.revparse_single(parent)?.peel_to_commit()?;
+            Ok(self
+                .write_commit(None, &parent, patches, message, author)?
+                .to_string())
+        }
+
+        fn finish(&self) -> Result<()> {
+            match self.apply_to {
+                ApplyTo::WorkTree => self
+                    .repo
+                    .checkout_head(Some(CheckoutBuilder::new().force()))?,
+                ApplyTo::Index => {
+                    let head = self.repo.head()?.peel_to_tree()?;
+                    let mut index = self.repo.index()?;
+                    index.read_tree(&head)?;
+                    index.write()?;
+                }
+            }
+            Ok(())
+        }
+
+        fn reset(&self, commit: &str) -> Result<()> {
+            let reset_type = match self.apply_to {
+                ApplyTo::WorkTree => ResetType::Hard,
+                ApplyTo::Index => ResetType::Mixed,
+            };
+            let commit = self.repo.revparse_single(commit)?;
+            self.repo.reset(&commit, reset_type, None)?;
+            Ok(())
+        }
+    }
+}
+
+/// The in-process backend if it's built in and can do everything `git`
+/// would, otherwise `git`. `KGIT_SPLIT_BACKEND=git` forces `git`.
+pub fn open(
+    diff_context_size: usize,
+    apply_to: ApplyTo,
+    options: &CommitOptions,
+) -> Box<dyn Backend> {
+    let subprocess = Subprocess {
+        diff_context_size,
+        apply_to,
+        options: options.clone(),
+    };
+    if std::env::var(crate::ENV_BACKEND).is_ok_and(|backend| backend == "git") {
+        return Box::new(subprocess);
+    }
+    #[cfg(feature = "libgit2")]
+    match InProcess::open(diff_context_size, apply_to) {
+        Ok(backend) if !backend.needs_git(options) => return Box::new(backend),
+        Ok(_) => log::debug!("Using git for hooks or signing"),
+        Err(err) => log::warn!("Falling back to git: {err:?}"),
+    }
+    Box::new(subprocess)
+}
@@ -0,0 +1,162 @@
+use crate::diff::{FileDiff, HunkHeader};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
+
+static DEFINITION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"\b(?:fn|struct|enum|trait|type|const|static|mod|union|macro_rules!|def|class|function|func)\s+([A-Za-z_][A-Za-z0-9_]*)",
+    )
+    .unwrap()
+});
+static IDENTIFIER: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap());
+
+/// What the warnings need to know about a diff, worked out once per change
+/// to it rather than on every redraw.
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    /// The index of the file each hunk is in.
+    pub file_of_hunk: Vec<usize>,
+    pub dependencies: Vec<Dependency>,
+    pub moves: Vec<Move>,
+}
+
+impl Analysis {
+    pub fn new(files: &[FileDiff]) -> Self {
+        Self {
+            file_of_hunk: files
+                .iter()
+                .enumerate()
+                .flat_map(|(file_id, (_, hunks))| std::iter::repeat_n(file_id, hunks.len()))
+                .collect(),
+            dependencies: dependencies(files),
+            moves: moved_blocks(files),
+        }
+    }
+}
+
+/// Hunk `hunk` is unlikely to apply or build without hunk `on`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    pub hunk: usize,
+    pub on: usize,
+    pub reason: String,
+}
+
+/// Names defined on the added lines of a hunk.
+fn definitions<'a>(pattern: &Regex, hunk: &'a str) -> Vec<&'a str> {
+    hunk.split('\n')
+        .skip(1)
+        .filter_map(|line| line.strip_prefix('+'))
+        .flat_map(|line| pattern.captures_iter(line))
+        .map(|cap| cap.get(1).unwrap().as_str())
+        .collect()
+}
+
+/// Finds hunks that depend on each other: hunks of one file whose line
+/// ranges overlap or touch (the later one depends on the earlier one), and
+/// hunks using a name that another hunk defines.
+fn dependencies(files: &[FileDiff]) -> Vec<Dependency> {
+    let mut deps = Vec::new();
+    let mut first_hunk = 0;
+    for (_, hunks) in files {
+        let ranges = hunks
+            .iter()
+            .enumerate()
+            .filter_map(|(hunk_id, hunk)| Some((HunkHeader::parse(hunk)?, first_hunk + hunk_id)))
+            .sorted_by_key(|(range, _)| range.old_start)
+            .collect_vec();
+        for ((a, a_idx), (b, b_idx)) in ranges.iter().tuple_combinations() {
+            if a.touches(b) {
+                deps.push(Dependency {
+                    hunk: *b_idx,
+                    on: *a_idx,
+                    reason: "touches the lines of".to_string(),
+                });
+            }
+        }
+        first_hunk += hunks.len();
+    }
+
+    let hunks = files.iter().flat_map(|(_, hunks)| hunks).collect_vec();
+    let mut defined_in: BTreeMap<&str, usize> = BTreeMap::new();
+    for (idx, hunk) in hunks.iter().enumerate() {
+        for name in definitions(&DEFINITION, hunk) {
+            defined_in.entry(name).or_insert(idx);
+        }
+    }
+    if defined_in.is_empty() {
+        return deps;
+    }
+    for (idx, hunk) in hunks.iter().enumerate() {
+        let used = hunk
+            .split('\n')
+            .skip(1)
+            .filter_map(|line| line.strip_prefix('+'))
+            .flat_map(|line| IDENTIFIER.find_iter(line).map(|m| m.as_str()))
+            .unique();
+        for name in used {
+            match defined_in.get(name) {
+                Some(&on) if on != idx => deps.push(Dependency {
+                    hunk: idx,
+                    on,
+                    reason: format!("uses `{name}` defined in"),
+                }),
+                _ => {}
+            }
+        }
+    }
+    deps
+}
+
+/// Fewer shared lines than this are more likely coincidence than a move.
+const MIN_MOVED_LINES: usize = 3;
+
+/// Lines removed from hunk `from` that hunk `to` adds again, like git's
+/// `--color-moved`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Move {
+    pub from: usize,
+    pub to: usize,
+    pub lines: usize,
+}
+
+/// Links hunks that remove a block of lines to the hunks adding them back.
+/// Indentation is ignored, and so are blank or very short lines.
+fn moved_blocks(files: &[FileDiff]) -> Vec<Move> {
+    let hunks = files.iter().flat_map(|(_, hunks)| hunks).collect_vec();
+    let changed = |hunk: &str, prefix: char| {
+        hunk.split('\n')
+            .skip(1)
+            .filter_map(|line| line.strip_prefix(prefix))
+            .map(str::trim)
+            .filter(|line| line.len() > 2)
+            .map(str::to_string)
+            .collect_vec()
+    };
+    let mut added_in: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (idx, hunk) in hunks.iter().enumerate() {
+        for line in changed(hunk, '+').into_iter().unique() {
+            added_in.entry(line).or_default().push(idx);
+        }
+    }
+    let mut moves = Vec::new();
+    for (from, hunk) in hunks.iter().enumerate() {
+        let mut shared: BTreeMap<usize, usize> = BTreeMap::new();
+        for line in changed(hunk, '-') {
+            for &to in added_in.get(&line).into_iter().flatten() {
+                if to != from {
+                    *shared.entry(to).or_default() += 1;
+                }
+            }
+        }
+        moves.extend(
+            shared
+                .into_iter()
+                .filter(|(_, lines)| *lines >= MIN_MOVED_LINES)
+                .map(|(to, lines)| Move { from, to, lines }),
+        );
+    }
+    moves
+}
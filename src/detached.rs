@@ -0,0 +1,127 @@
+//! Splitting a commit without a rebase or touching the checkout: the new
+//! commits are built in a private index, the commits after the split one
+//! are re-parented onto them, and the branch is moved with one ref update.
+//! No commit hooks run, so neither can a `commit-msg` hook rewrite messages.
+
+use crate::backend::{self, ApplyTo, CommitOptions};
+use crate::diff::parse_diff;
+use crate::save::{self, MessageFormat};
+use crate::ui::{self, CommitInfo, UiState};
+use crate::{get_output, git, rev_parse};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+/// Recreates `commit` with the same tree and message on other parents.
+fn reparent(commit: &str, parents: &[String], commit_options: &CommitOptions) -> Result<String> {
+    let original = save::Original::read(commit, false)?;
+    let message = get_output(git().args(["log", "-1", "--format=%B", commit]))?;
+    let mut cmd = git();
+    cmd.args(["commit-tree", &format!("{commit}^{{tree}}")])
+        .args(parents.iter().flat_map(|parent| ["-p", parent]))
+        .args(["-m", message.trim_end()])
+        .args(commit_options.sign_args());
+    backend::set_author(&mut cmd, original.author.as_ref());
+    Ok(get_output(&mut cmd)?.trim().to_string())
+}
+
+pub fn split_detached(
+    commit: &str,
+    diff_context_size: usize,
+    reset_author: bool,
+    commit_options: &CommitOptions,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let head = rev_parse("HEAD")?;
+    anyhow::ensure!(
+        git()
+            .args(["merge-base", "--is-ancestor", commit, &head])
+            .status()?
+            .success(),
+        "{commit} is not an ancestor of HEAD"
+    );
+    let parent = rev_parse(format!("{commit}~")).context("Can't split a root commit")?;
+    let raw_hunks = backend::open(diff_context_size, ApplyTo::Index, &Default::default())
+        .diff(&parent, Some(commit))?;
+    let original_commit_message = get_output(git().args(["log", "-1", "--format=%B", commit]))?;
+    let files = parse_diff(&raw_hunks)?;
+    // Where HEAD points: a branch, or nothing when detached.
+    let head_ref = get_output(git().args(["symbolic-ref", "--quiet", "HEAD"]))
+        .ok()
+        .map(|name| name.trim().to_string());
+
+    let mut ui_state = UiState {
+        files,
+        message_format,
+        source_commit: Some(commit.to_string()),
+        commit_options: commit_options.clone(),
+        ..Default::default()
+    };
+    ui_state.insert_message(
+        '0',
+        CommitInfo {
+            commit_message: original_commit_message,
+        },
+    );
+    ui_state
+        .hunk_commits
+        .extend((0..ui_state.hunk_count()).map(|_| Some('0')));
+
+    let original = save::Original::read(commit, reset_author)?;
+    let commit = commit.to_string();
+    let ui_state = ui::run(ui_state, move |plan, commit_options, progress| {
+        let commit_options = &commit_options.with_configured_signing();
+        let backend = backend::open(diff_context_size, ApplyTo::Index, commit_options);
+        let tip = save::save_onto(&plan, &*backend, &original, &parent, progress)?;
+        // The commits after `commit` keep their trees, which is only right
+        // if the new commits add up to it.
+        anyhow::ensure!(
+            rev_parse(format!("{tip}^{{tree}}"))? == rev_parse(format!("{commit}^{{tree}}"))?,
+            "The new commits don't add up to {commit}; \
+             splitting without a checkout needs every hunk assigned"
+        );
+
+        let mut replaced = BTreeMap::from([(commit.clone(), tip.clone())]);
+        let later = get_output(git().args([
+            "rev-list",
+            "--reverse",
+            "--topo-order",
+            "--ancestry-path",
+            "--parents",
+            &format!("{commit}..{head}"),
+        ]))?;
+        if !later.is_empty() {
+            progress.report(format!("Replaying {commit}..{head}"));
+        }
+        let mut new_head = tip;
+        for line in later.lines() {
+            let mut shas = line.split(' ').map(str::to_string);
+            let Some(sha) = shas.next() else { continue };
+            let old_parents = shas.collect::<Vec<_>>();
+            let parents = old_parents
+                .iter()
+                .map(|parent| replaced.get(parent).unwrap_or(parent).clone())
+                .collect::<Vec<_>>();
+            new_head = if parents == old_parents {
+                sha.clone()
+            } else {
+                reparent(&sha, &parents, commit_options)?
+            };
+            replaced.insert(sha, new_head.clone());
+        }
+
+        // Fails if HEAD moved in the meantime.
+        let reflog = format!("kgit-split: split {commit}");
+        let mut update_ref = git();
+        update_ref.args(["update-ref", "-m", &reflog]);
+        match &head_ref {
+            Some(name) => update_ref.arg(name),
+            None => update_ref.args(["--no-deref", "HEAD"]),
+        };
+        get_output(update_ref.args([&new_head, &head]))?;
+        Ok(())
+    })?;
+    if let Some(err) = ui_state.save_error {
+        anyhow::bail!("Failed to save the commits:\n{err}");
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use anyhow::Result;
+use regex::Regex;
+use std::fmt::Write as _;
+
+/// A file header (everything from `diff --git` up to the first `@@`) and the
+/// hunks that follow it.
+pub type FileDiff = (String, Vec<String>);
+
+pub fn parse_diff(raw_hunks: &str) -> Result<Vec<FileDiff>> {
+    let hunk_start_pat = Regex::new(r#"@@ \-\d+(?:,\d+)? \+\d+(?:,\d+)? @@"#)?;
+    let diff_start_pat = Regex::new(r#"diff .*"#)?;
+    let mut files = Vec::new();
+    enum ParseMode {
+        Hunk,
+        Header,
+    }
+    let mut lines = raw_hunks.split('\n');
+    let mut header = diff_start_pat
+        .captures(lines.next().expect("diff was empty"))
+        .expect("First line should be diff in diff")[0]
+        .to_string();
+    let mut hunks = Vec::new();
+    let mut parse_mode = ParseMode::Header;
+
+    for line in lines {
+        match parse_mode {
+            ParseMode::Hunk => {
+                if let Some(_cap) = diff_start_pat.captures(line) {
+                    files.push((std::mem::take(&mut header), std::mem::take(&mut hunks)));
+                    header.push_str(line);
+                    parse_mode = ParseMode::Header;
+                } else if let Some(_cap) = hunk_start_pat.captures(line) {
+                    hunks.push(line.to_string());
+                    parse_mode = ParseMode::Hunk;
+                } else {
+                    write!(hunks.last_mut().unwrap(), "\n{line}").unwrap();
+                    parse_mode = ParseMode::Hunk;
+                }
+            }
+            ParseMode::Header => {
+                if let Some(_cap) = diff_start_pat.captures(line) {
+                    files.push((std::mem::take(&mut header), std::mem::take(&mut hunks)));
+                    header.push_str(line);
+                    parse_mode = ParseMode::Header;
+                } else if let Some(_cap) = hunk_start_pat.captures(line) {
+                    hunks.push(line.to_string());
+                    parse_mode = ParseMode::Hunk;
+                } else {
+                    write!(header, "\n{line}").unwrap();
+                    parse_mode = ParseMode::Header;
+                }
+            }
+        }
+    }
+    if !header.is_empty() {
+        files.push((std::mem::take(&mut header), std::mem::take(&mut hunks)));
+    }
+    Ok(files)
+}
+
+/// The line ranges from a `@@ -a,b +c,d @@` hunk header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HunkHeader {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+}
+
+impl HunkHeader {
+    pub fn parse(hunk: &str) -> Option<Self> {
+        fn range(s: &str) -> Option<(usize, usize)> {
+            match s.split_once(',') {
+                Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+                None => Some((s.parse().ok()?, 1)),
+            }
+        }
+        let mut parts = hunk.strip_prefix("@@ ")?.split(' ');
+        let (old_start, old_len) = range(parts.next()?.strip_prefix('-')?)?;
+        let (new_start, new_len) = range(parts.next()?.strip_prefix('+')?)?;
+        Some(Self {
+            old_start,
+            old_len,
+            new_start,
+            new_len,
+        })
+    }
+
+    pub fn old_end(&self) -> usize {
+        self.old_start + self.old_len
+    }
+
+    /// Whether the old-side ranges of two hunks overlap or sit right next to
+    /// each other, in which case they can't be applied independently.
+    pub fn touches(&self, other: &Self) -> bool {
+        self.old_start <= other.old_end() && other.old_start <= self.old_end()
+    }
+}
+
+/// The path a file header is about, preferring the new name.
+pub fn file_name(header: &str) -> &str {
+    let mut old = None;
+    for line in header.split('\n') {
+        if let Some(name) = line.strip_prefix("+++ ") {
+            if name != "/dev/null" {
+                return name.strip_prefix("b/").unwrap_or(name);
+            }
+        } else if let Some(name) = line.strip_prefix("--- ") {
+            old = Some(name.strip_prefix("a/").unwrap_or(name));
+        }
+    }
+    old.or_else(|| header.split('\n').next()?.rsplit(" b/").next())
+        .unwrap_or(header)
+}
+
+/// The number of added and removed lines in a hunk.
+pub fn hunk_stat(hunk: &str) -> (usize, usize) {
+    hunk.split('\n')
+        .skip(1)
+        .fold((0, 0), |(added, removed), line| {
+            match line.as_bytes().first() {
+                Some(b'+') => (added + 1, removed),
+                Some(b'-') => (added, removed + 1),
+                _ => (added, removed),
+            }
+        })
+}
+
+/// Whether a hunk only changes whitespace: its removed and added lines are
+/// the same once all whitespace is dropped, so reflowed lines count too.
+pub fn is_whitespace_only(hunk: &str) -> bool {
+    let side = |prefix: char| {
+        hunk.split('\n')
+            .skip(1)
+            .filter_map(|line| line.strip_prefix(prefix))
+            .flat_map(|line| line.chars())
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+    };
+    hunk.split('\n')
+        .skip(1)
+        .any(|line| line.starts_with(['+', '-']))
+        && side('-') == side('+')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_whitespace_only;
+
+    #[test]
+    fn whitespace_only_hunks() {
+        assert!(is_whitespace_only("@@ -1 +1 @@\n-a  b\n+a b\n c"));
+        // Reflowed lines still only change whitespace.
+        assert!(is_whitespace_only(
+            "@@ -1,2 +1 @@\n-foo(a,\n-    b)\n+foo(a, b)"
+        ));
+        assert!(is_whitespace_only("@@ -1,0 +1 @@\n+   "));
+    }
+
+    #[test]
+    fn hunks_with_other_changes() {
+        assert!(!is_whitespace_only("@@ -1 +1 @@\n-a b\n+a c"));
+        assert!(!is_whitespace_only("@@ -1 +1 @@\n+x"));
+        // A hunk header or context lines alone change nothing.
+        assert!(!is_whitespace_only("@@ -1 +1 @@\n a"));
+    }
+}
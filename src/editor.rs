@@ -0,0 +1,296 @@
+use crate::fmt::FmtFn;
+use crate::theme::{theme, Style};
+
+pub const SUBJECT_WIDTH: usize = 50;
+pub const BODY_WIDTH: usize = 72;
+
+/// A small multi-line text editor for commit messages. `col` is a char index
+/// into `lines[row]`.
+#[derive(Debug, Clone)]
+pub struct TextEditor {
+    lines: Vec<String>,
+    row: usize,
+    col: usize,
+}
+
+impl Default for TextEditor {
+    fn default() -> Self {
+        Self {
+            lines: vec![String::new()],
+            row: 0,
+            col: 0,
+        }
+    }
+}
+
+impl TextEditor {
+    /// Starts with the cursor at the end of the subject line.
+    pub fn new(text: &str) -> Self {
+        let mut lines = text
+            .trim_end()
+            .split('\n')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        let col = lines[0].chars().count();
+        Self { lines, row: 0, col }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.truncate(text.trim_end().len());
+        text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.iter().all(|line| line.trim().is_empty())
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.lines[0]
+    }
+
+    fn line_len(&self, row: usize) -> usize {
+        self.lines[row].chars().count()
+    }
+
+    fn byte_idx(&self, col: usize) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices().nth(col).map_or(line.len(), |(i, _)| i)
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        if c == '\n' {
+            return self.insert_newline();
+        }
+        let idx = self.byte_idx(self.col);
+        self.lines[self.row].insert(idx, c);
+        self.col += 1;
+    }
+
+    pub fn insert_newline(&mut self) {
+        let idx = self.byte_idx(self.col);
+        let rest = self.lines[self.row].split_off(idx);
+        self.row += 1;
+        self.col = 0;
+        self.lines.insert(self.row, rest);
+    }
+
+    pub fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            let idx = self.byte_idx(self.col);
+            self.lines[self.row].remove(idx);
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.line_len(self.row);
+            self.lines[self.row].push_str(&line);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.col < self.line_len(self.row) {
+            let idx = self.byte_idx(self.col);
+            self.lines[self.row].remove(idx);
+        } else if self.row + 1 < self.lines.len() {
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].push_str(&line);
+        }
+    }
+
+    /// Deletes back to the start of the previous word, like readline's `C-w`.
+    pub fn delete_word(&mut self) {
+        if self.col == 0 {
+            return self.backspace();
+        }
+        let chars = self.lines[self.row].chars().collect::<Vec<_>>();
+        let mut start = self.col;
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        let (from, to) = (self.byte_idx(start), self.byte_idx(self.col));
+        self.lines[self.row].replace_range(from..to, "");
+        self.col = start;
+    }
+
+    pub fn left(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = self.line_len(self.row);
+        }
+    }
+
+    pub fn right(&mut self) {
+        if self.col < self.line_len(self.row) {
+            self.col += 1;
+        } else if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.col = 0;
+        }
+    }
+
+    pub fn up(&mut self) {
+        if self.row > 0 {
+            self.row -= 1;
+            self.col = self.col.min(self.line_len(self.row));
+        }
+    }
+
+    pub fn down(&mut self) {
+        if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.col = self.col.min(self.line_len(self.row));
+        }
+    }
+
+    pub fn home(&mut self) {
+        self.col = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.col = self.line_len(self.row);
+    }
+
+    /// Problems with the subject/body layout, as shown under the editor.
+    pub fn lint(&self) -> Option<&'static str> {
+        if self
+            .lines
+            .get(1)
+            .is_some_and(|line| !line.trim().is_empty())
+        {
+            Some("separate the subject from the body with a blank line")
+        } else if self.subject().chars().count() > SUBJECT_WIDTH {
+            Some("subject is longer than 50 characters")
+        } else {
+            None
+        }
+    }
+
+    /// The message with the cursor shown in inverse video and anything past
+    /// the subject/body width limits highlighted.
+    pub fn render(&self) -> impl std::fmt::Display + '_ {
+        let theme = theme();
+        FmtFn(move |f| {
+            writeln!(
+                f,
+                "{}{:-<subject$}|{:-<body$}|{}",
+                theme.paint(theme.faint),
+                "",
+                "",
+                theme.reset(),
+                subject = SUBJECT_WIDTH,
+                body = BODY_WIDTH - SUBJECT_WIDTH - 1,
+            )?;
+            for (row, line) in self.lines.iter().enumerate() {
+                let limit = if row == 0 { SUBJECT_WIDTH } else { BODY_WIDTH };
+                let line_style = Style {
+                    bold: row == 0,
+                    ..Style::PLAIN
+                };
+                let over_style = Style {
+                    bold: row == 0,
+                    ..theme.error
+                };
+                write!(f, "{}", theme.paint(line_style))?;
+                let len = line.chars().count();
+                for (col, c) in line.chars().chain(std::iter::once(' ')).enumerate() {
+                    let style = if col >= limit { over_style } else { line_style };
+                    if col == limit {
+                        write!(f, "{}", theme.paint(style))?;
+                    }
+                    if (row, col) == (self.row, self.col) {
+                        write!(
+                            f,
+                            "{}{c}{}{}",
+                            theme.paint(Style {
+                                reverse: true,
+                                ..style
+                            }),
+                            theme.reset(),
+                            theme.paint(style),
+                        )?;
+                    } else if col < len {
+                        write!(f, "{c}")?;
+                    }
+                }
+                writeln!(f, "{}", theme.reset())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_text(editor: &mut TextEditor, text: &str) {
+        text.chars().for_each(|c| editor.insert_char(c));
+    }
+
+    #[test]
+    fn starts_at_the_end_of_the_subject() {
+        let mut editor = TextEditor::new("Fix ü\n\nBody\n\n");
+        type_text(&mut editor, "ber");
+        assert_eq!(editor.text(), "Fix über\n\nBody");
+        assert_eq!(TextEditor::new("").text(), "");
+        assert!(TextEditor::new(" \n ").is_empty());
+    }
+
+    #[test]
+    fn joins_and_splits_lines() {
+        let mut editor = TextEditor::new("subjectbody");
+        (0..4).for_each(|_| editor.left());
+        editor.insert_newline();
+        editor.insert_newline();
+        assert_eq!(editor.text(), "subject\n\nbody");
+        editor.backspace();
+        assert_eq!(editor.text(), "subject\nbody");
+        editor.up();
+        editor.end();
+        editor.delete();
+        assert_eq!(editor.text(), "subjectbody");
+    }
+
+    #[test]
+    fn deletes_words() {
+        let mut editor = TextEditor::new("Add  the thing  ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "Add  the");
+        editor.delete_word();
+        editor.delete_word();
+        assert_eq!(editor.text(), "");
+        editor.delete_word();
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn moves_across_lines() {
+        let mut editor = TextEditor::new("ab\nc");
+        editor.right();
+        type_text(&mut editor, "x");
+        assert_eq!(editor.text(), "ab\nxc");
+        editor.up();
+        editor.home();
+        editor.left();
+        type_text(&mut editor, "y");
+        assert_eq!(editor.text(), "yab\nxc");
+    }
+
+    #[test]
+    fn lints_the_layout() {
+        assert_eq!(TextEditor::new("Subject\n\nBody").lint(), None);
+        assert!(TextEditor::new("Subject\nBody").lint().is_some());
+        assert!(TextEditor::new(&"x".repeat(SUBJECT_WIDTH + 1))
+            .lint()
+            .is_some());
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::Result;
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use termion::event::Key;
+use termion::input::TermRead;
+
+pub const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum Event {
+    Key(Key),
+    /// Stdin was closed.
+    InputClosed,
+    /// SIGWINCH.
+    Resize,
+    /// Nothing else happened for `TICK`.
+    Tick,
+    /// A line of progress from a background task.
+    Progress(String),
+    /// A background task finished.
+    TaskDone(Result<(), String>),
+}
+
+/// Lets a background task report to the UI.
+#[derive(Clone)]
+pub struct Progress(Sender<Event>);
+
+impl Progress {
+    pub fn report(&self, message: impl Into<String>) {
+        let _ = self.0.send(Event::Progress(message.into()));
+    }
+
+    pub fn finish(self, result: Result<()>) {
+        let _ = self
+            .0
+            .send(Event::TaskDone(result.map_err(|err| format!("{err:?}"))));
+    }
+}
+
+/// Merges keys, terminal resizes and background task updates into one
+/// stream.
+pub struct Events {
+    tx: Sender<Event>,
+    rx: Receiver<Event>,
+    paused: Arc<AtomicBool>,
+    input_lock: Arc<Mutex<()>>,
+}
+
+/// Waits up to `timeout` for stdin to become readable.
+fn poll_stdin(timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+impl Events {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let paused = Arc::new(AtomicBool::new(false));
+        let input_lock = Arc::new(Mutex::new(()));
+        {
+            let tx = tx.clone();
+            let paused = paused.clone();
+            let input_lock = input_lock.clone();
+            std::thread::spawn(move || {
+                // Read the fd directly: `std::io::Stdin` buffers, which would
+                // hide pending bytes from `poll`.
+                let stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+                let mut keys = (&*stdin).keys();
+                loop {
+                    if paused.load(Ordering::SeqCst) {
+                        std::thread::sleep(TICK);
+                        continue;
+                    }
+                    let _guard = input_lock.lock().unwrap();
+                    if !poll_stdin(TICK) {
+                        continue;
+                    }
+                    let event = match keys.next() {
+                        Some(Ok(key)) => Event::Key(key),
+                        Some(Err(err)) => {
+                            log::error!("Failed to read key {err:?}");
+                            continue;
+                        }
+                        None => Event::InputClosed,
+                    };
+                    let closed = matches!(event, Event::InputClosed);
+                    if tx.send(event).is_err() || closed {
+                        break;
+                    }
+                }
+            });
+        }
+        {
+            let tx = tx.clone();
+            let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGWINCH])?;
+            std::thread::spawn(move || {
+                for _ in signals.forever() {
+                    if tx.send(Event::Resize).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Self {
+            tx,
+            rx,
+            paused,
+            input_lock,
+        })
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress(self.tx.clone())
+    }
+
+    /// Blocks until the next event, or returns `Event::Tick` after `TICK`.
+    pub fn next(&self) -> Event {
+        match self.rx.recv_timeout(TICK) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => Event::Tick,
+            Err(RecvTimeoutError::Disconnected) => Event::InputClosed,
+        }
+    }
+
+    /// Stops reading keys while `f` runs, so that a pager or editor started
+    /// by `f` gets all of the input.
+    pub fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.paused.store(true, Ordering::SeqCst);
+        let result = {
+            let _guard = self.input_lock.lock().unwrap();
+            f()
+        };
+        self.paused.store(false, Ordering::SeqCst);
+        result
+    }
+}
@@ -1,3 +1,6 @@
+// Grab-bag of formatting helpers; not all of them are used at any one time.
+#![allow(dead_code)]
+
 use std::fmt::{self, Debug, Display};
 
 pub struct OrDisplay<T: Display, U>(Option<T>, U);
@@ -66,7 +69,7 @@ impl std::fmt::Display for ThousandsSigned {
         if self.0 < 0 {
             write!(f, "-")?;
         }
-        write!(f, "{}", ThousandsUnsigned(self.0.abs() as u64))
+        write!(f, "{}", ThousandsUnsigned(self.0.unsigned_abs()))
     }
 }
 
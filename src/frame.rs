@@ -0,0 +1,134 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Keeps the rows that are currently on screen so that drawing a new frame
+/// only rewrites the rows that changed.
+pub struct Screen<W: Write> {
+    out: W,
+    rows: Vec<String>,
+    size: (u16, u16),
+    buffer: String,
+}
+
+impl<W: Write> Screen<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            rows: Vec::new(),
+            size: (0, 0),
+            buffer: String::new(),
+        }
+    }
+
+    /// Forget what is on screen, so the next `draw` clears and rewrites
+    /// everything. Needed after another program (pager, editor) has used the
+    /// terminal.
+    pub fn invalidate(&mut self) {
+        self.rows.clear();
+    }
+
+    /// Draws `text` from the top of the screen and `status` on the last row.
+    /// Rows are cut off at the terminal width instead of wrapping.
+    pub fn draw(&mut self, size: (u16, u16), text: &str, status: &str) -> io::Result<()> {
+        if size != self.size {
+            self.size = size;
+            self.invalidate();
+        }
+        let (width, height) = (size.0 as usize, (size.1 as usize).max(1));
+        let mut rows = text
+            .split('\n')
+            .take(height - 1)
+            .map(|line| fit(line, width))
+            .collect::<Vec<_>>();
+        rows.resize(height - 1, String::new());
+        rows.push(fit(status, width));
+
+        self.buffer.clear();
+        if self.rows.is_empty() {
+            write!(self.buffer, "{}", termion::clear::All).unwrap();
+        }
+        for (idx, row) in rows.iter().enumerate() {
+            if self.rows.get(idx) == Some(row) {
+                continue;
+            }
+            write!(
+                self.buffer,
+                "{goto}{reset}{row}{reset}{clear}",
+                goto = termion::cursor::Goto(1, idx as u16 + 1),
+                reset = termion::style::Reset,
+                clear = termion::clear::UntilNewline,
+            )
+            .unwrap();
+        }
+        self.rows = rows;
+        if !self.buffer.is_empty() {
+            self.out.write_all(self.buffer.as_bytes())?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Cuts `line` down to `width` visible cells, passing escape sequences through
+/// and expanding tabs.
+fn fit(line: &str, width: usize) -> String {
+    let mut fitted = String::with_capacity(line.len());
+    let mut cells = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                fitted.push(c);
+                // CSI sequences end with a byte in '@'..='~'.
+                if let Some(c) = chars.next() {
+                    fitted.push(c);
+                    if c == '[' {
+                        for c in chars.by_ref() {
+                            fitted.push(c);
+                            if ('@'..='~').contains(&c) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            '\t' => {
+                let tab = (8 - cells % 8).min(width - cells);
+                fitted.extend(std::iter::repeat_n(' ', tab));
+                cells += tab;
+            }
+            '\r' => {}
+            c if cells < width => {
+                fitted.push(c);
+                cells += 1;
+            }
+            _ => {}
+        }
+    }
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fit;
+
+    #[test]
+    fn fit_cuts_to_width() {
+        assert_eq!(fit("hello", 3), "hel");
+        assert_eq!(fit("héllo", 10), "héllo");
+        assert_eq!(fit("a\r", 3), "a");
+    }
+
+    #[test]
+    fn fit_passes_escapes_through() {
+        assert_eq!(fit("\x1b[1;31mred\x1b[m", 2), "\x1b[1;31mre\x1b[m");
+        assert_eq!(fit("\x1b7ab", 1), "\x1b7a");
+    }
+
+    #[test]
+    fn fit_expands_tabs() {
+        assert_eq!(fit("a\tb", 10), "a       b");
+        assert_eq!(fit("a\tb", 4), "a   ");
+        assert_eq!(fit("\t", 0), "");
+    }
+}
@@ -0,0 +1,234 @@
+use crate::ui::UiMode;
+use std::fmt;
+use termion::event::Key;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    // Viewing
+    TogglePartial,
+    ToggleNoVerify,
+    Redraw,
+    ToggleSideBySide,
+    EditHunk,
+    Pager,
+    Save,
+    Quit,
+    ShowCommits,
+    AssignCommit,
+    Unassign,
+    ToggleSelection,
+    ClearSelection,
+    AssignUnassigned,
+    AssignFile,
+    EditMessage,
+    Suggest,
+    SplitFormatting,
+    PrevHunk,
+    NextHunk,
+    Help,
+    // CommitList
+    SelectPrev,
+    SelectNext,
+    MoveCommitUp,
+    MoveCommitDown,
+    EditSelected,
+    PreviewCommit,
+    // Suggesting
+    NextStrategy,
+    AcceptGroup,
+    AcceptAll,
+    // Preview
+    ScrollUp,
+    ScrollDown,
+    PageUp,
+    PageDown,
+    // Editing
+    InsertChar,
+    Newline,
+    DeleteChar,
+    DeleteForward,
+    DeleteWord,
+    CursorLeft,
+    CursorRight,
+    CursorUp,
+    CursorDown,
+    LineStart,
+    LineEnd,
+    ExternalEditor,
+    Confirm,
+    // WaitingToEdit
+    PickCommit,
+    // Shared
+    Back,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum KeyPattern {
+    Exact(Key),
+    Digit,
+    AnyChar,
+    AnyKey,
+}
+
+impl KeyPattern {
+    pub fn matches(&self, key: Key) -> bool {
+        match (self, key) {
+            (Self::Exact(expected), key) => *expected == key,
+            (Self::Digit, Key::Char(c)) => c.is_ascii_digit(),
+            (Self::AnyChar, Key::Char(_)) => true,
+            (Self::AnyKey, _) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact(Key::Char('\n')) => write!(f, "Enter"),
+            Self::Exact(Key::Char('\t')) => write!(f, "Tab"),
+            Self::Exact(Key::Char(' ')) => write!(f, "Space"),
+            Self::Exact(Key::Char(c)) => write!(f, "{c}"),
+            Self::Exact(Key::Ctrl(c)) => write!(f, "C-{c}"),
+            Self::Exact(Key::Alt(c)) => write!(f, "M-{c}"),
+            Self::Exact(Key::F(n)) => write!(f, "F{n}"),
+            Self::Exact(key) => write!(f, "{key:?}"),
+            Self::Digit => write!(f, "0-9"),
+            Self::AnyChar => write!(f, "<char>"),
+            Self::AnyKey => write!(f, "<any>"),
+        }
+    }
+}
+
+pub struct Binding {
+    pub keys: &'static [KeyPattern],
+    pub action: Action,
+    /// Shown in the hint bar.
+    pub name: &'static str,
+    /// Shown in the help overlay.
+    pub description: &'static str,
+}
+
+macro_rules! bindings {
+    ($([$($key:expr),+] => $action:ident, $name:literal, $description:literal;)*) => {
+        &[$(Binding {
+            keys: &[$($key),+],
+            action: Action::$action,
+            name: $name,
+            description: $description,
+        }),*]
+    };
+}
+
+use KeyPattern::*;
+
+const VIEWING: &[Binding] = bindings! {
+    [Exact(Key::Char('?'))] => Help, "help", "Show this help";
+    [Exact(Key::Left)] => PrevHunk, "prev", "Go to the previous hunk";
+    [Exact(Key::Right)] => NextHunk, "next", "Go to the next hunk";
+    [Digit] => AssignCommit, "assign", "Assign the hunk (or selection) to a commit, creating it if needed";
+    [Exact(Key::Char('v'))] => ToggleSelection, "select", "Start or drop a selection of hunks from the current one";
+    [Exact(Key::Esc)] => ClearSelection, "unselect", "Drop the selection";
+    [Exact(Key::Char('u'))] => Unassign, "unassign", "Unassign the hunk (or selection)";
+    [Exact(Key::Char('a'))] => AssignUnassigned, "assign rest", "Assign all unassigned hunks to a commit";
+    [Exact(Key::Char('f'))] => AssignFile, "assign file", "Assign all hunks in this file to a commit";
+    [Exact(Key::Backspace)] => EditMessage, "edit msg", "Edit the message of a commit";
+    [Exact(Key::Char('\t'))] => ShowCommits, "commits", "Show the commit list to reorder commits";
+    [Exact(Key::Char('W'))] => SplitFormatting, "formatting", "Move all whitespace-only hunks into a \"Formatting\" commit";
+    [Exact(Key::Char('g'))] => Suggest, "suggest", "Suggest groups of hunks to assign to new commits";
+    [Exact(Key::Ctrl('e'))] => EditHunk, "edit hunk", "Split the hunk by editing it in $EDITOR";
+    [Exact(Key::Char('p'))] => Pager, "pager", "Show the hunk in $PAGER";
+    [Exact(Key::Char('s'))] => ToggleSideBySide, "side by side", "Toggle showing old and new side by side (on wide terminals)";
+    [Exact(Key::Ctrl('f'))] => TogglePartial, "partial", "Allow saving with unassigned hunks";
+    [Exact(Key::Ctrl('n'))] => ToggleNoVerify, "no verify", "Toggle skipping the pre-commit and commit-msg hooks";
+    [Exact(Key::Ctrl('l'))] => Redraw, "redraw", "Redraw the screen";
+    [Exact(Key::Ctrl('s'))] => Save, "save", "Create the commits and exit";
+    [Exact(Key::Char('q'))] => Quit, "quit", "Abort the rebase and exit";
+};
+
+const COMMIT_LIST: &[Binding] = bindings! {
+    [Exact(Key::Char('?'))] => Help, "help", "Show this help";
+    [Exact(Key::Up), Exact(Key::Char('k'))] => SelectPrev, "up", "Select the previous commit";
+    [Exact(Key::Down), Exact(Key::Char('j'))] => SelectNext, "down", "Select the next commit";
+    [Exact(Key::Char('K'))] => MoveCommitUp, "move up", "Create the selected commit earlier";
+    [Exact(Key::Char('J'))] => MoveCommitDown, "move down", "Create the selected commit later";
+    [Exact(Key::Char('\n'))] => EditSelected, "edit msg", "Edit the selected commit's message";
+    [Exact(Key::Char('v')), Exact(Key::Char(' '))] => PreviewCommit, "preview", "Show the patch the selected commit will be created from";
+    [Exact(Key::Char('\t')), Exact(Key::Esc)] => Back, "back", "Go back to the hunks";
+};
+
+const SUGGESTING: &[Binding] = bindings! {
+    [Exact(Key::Char('?'))] => Help, "help", "Show this help";
+    [Exact(Key::Up), Exact(Key::Char('k'))] => SelectPrev, "up", "Select the previous group";
+    [Exact(Key::Down), Exact(Key::Char('j'))] => SelectNext, "down", "Select the next group";
+    [Exact(Key::Char('\t'))] => NextStrategy, "strategy", "Group the hunks another way";
+    [Exact(Key::Char('\n'))] => AcceptGroup, "accept", "Assign the group's hunks to its commit, editing the message if it is new";
+    [Exact(Key::Char('A'))] => AcceptAll, "accept all", "Assign every group to its commit, using the labels as messages";
+    [Exact(Key::Esc), Exact(Key::Char('q'))] => Back, "back", "Go back to the hunks";
+};
+
+const PREVIEW: &[Binding] = bindings! {
+    [Exact(Key::Char('?'))] => Help, "help", "Show this help";
+    [Exact(Key::Up), Exact(Key::Char('k'))] => ScrollUp, "up", "Scroll up a line";
+    [Exact(Key::Down), Exact(Key::Char('j'))] => ScrollDown, "down", "Scroll down a line";
+    [Exact(Key::PageUp), Exact(Key::Char('b'))] => PageUp, "page up", "Scroll up a page";
+    [Exact(Key::PageDown), Exact(Key::Char(' '))] => PageDown, "page down", "Scroll down a page";
+    [Exact(Key::Char('p'))] => Pager, "pager", "Show the patch in $PAGER";
+    [Exact(Key::Esc), Exact(Key::Char('q'))] => Back, "back", "Go back to the commit list";
+};
+
+const EDITING: &[Binding] = bindings! {
+    [Exact(Key::Ctrl('s'))] => Confirm, "done", "Save the commit message";
+    [Exact(Key::Esc)] => Back, "cancel", "Discard the changes to the commit message";
+    [Exact(Key::Char('\n'))] => Newline, "newline", "Insert a line break";
+    [Exact(Key::Backspace)] => DeleteChar, "delete", "Delete the character before the cursor";
+    [Exact(Key::Delete), Exact(Key::Ctrl('d'))] => DeleteForward, "delete fwd", "Delete the character under the cursor";
+    [Exact(Key::Ctrl('w'))] => DeleteWord, "delete word", "Delete the word before the cursor";
+    [Exact(Key::Left), Exact(Key::Ctrl('b'))] => CursorLeft, "left", "Move the cursor left";
+    [Exact(Key::Right), Exact(Key::Ctrl('f'))] => CursorRight, "right", "Move the cursor right";
+    [Exact(Key::Up), Exact(Key::Ctrl('p'))] => CursorUp, "up", "Move the cursor up a line";
+    [Exact(Key::Down), Exact(Key::Ctrl('n'))] => CursorDown, "down", "Move the cursor down a line";
+    [Exact(Key::Home), Exact(Key::Ctrl('a'))] => LineStart, "home", "Move to the start of the line";
+    [Exact(Key::End), Exact(Key::Ctrl('e'))] => LineEnd, "end", "Move to the end of the line";
+    [Exact(Key::Ctrl('o'))] => ExternalEditor, "$EDITOR", "Continue editing the message in $EDITOR";
+    [AnyChar] => InsertChar, "type", "Insert text at the cursor";
+};
+
+const WAITING_TO_EDIT: &[Binding] = bindings! {
+    [Digit] => PickCommit, "edit", "Edit the message of that commit";
+    [AnyKey] => Back, "cancel", "Go back";
+};
+
+const SAVING: &[Binding] = bindings! {
+    [AnyKey] => Back, "back", "Go back to the hunks after saving failed, to change things and save again";
+};
+
+const HELP: &[Binding] = bindings! {
+    [AnyKey] => Back, "close", "Close the help";
+};
+
+const WAITING_TO_ASSIGN: &[Binding] = bindings! {
+    [Digit] => PickCommit, "assign", "Assign the hunks to that commit, creating it if needed";
+    [AnyKey] => Back, "cancel", "Go back";
+};
+
+pub fn bindings(mode: &UiMode) -> &'static [Binding] {
+    match mode {
+        UiMode::Viewing { .. } => VIEWING,
+        UiMode::CommitList { .. } => COMMIT_LIST,
+        UiMode::Preview { .. } => PREVIEW,
+        UiMode::Suggesting { .. } => SUGGESTING,
+        UiMode::Editing { .. } => EDITING,
+        UiMode::WaitingToEdit => WAITING_TO_EDIT,
+        UiMode::WaitingToAssign { .. } => WAITING_TO_ASSIGN,
+        UiMode::Help => HELP,
+        UiMode::Saving { .. } => SAVING,
+    }
+}
+
+pub fn lookup(mode: &UiMode, key: Key) -> Option<Action> {
+    bindings(mode)
+        .iter()
+        .find(|binding| binding.keys.iter().any(|pattern| pattern.matches(key)))
+        .map(|binding| binding.action)
+}
@@ -1,37 +1,143 @@
-mod hash;
-use hash::*;
+mod absorb;
+mod backend;
 mod fmt;
 use fmt::*;
+mod deps;
+mod detached;
+mod diff;
+use diff::*;
+mod editor;
+mod event;
+mod frame;
+mod keymap;
+mod save;
+mod session;
+mod suggest;
+mod theme;
+mod worktree;
+use theme::{theme, Style};
+mod ui;
+use backend::{CommitOptions, Sign};
+use ui::*;
 
 use anyhow::{Context, Result};
 use itertools::Itertools;
-use regex::Regex;
-use std::fmt::Write as _;
-use std::io::{stdout, Write as _};
 use std::path::PathBuf;
 use std::process::Command;
 
 // yes n | git reset -p | luajit -e 'a = io.read"*a"; for x in a:gmatch [[@@ %-%d+,%d+ %+%d+,%d+ @@(.-)%(%d+/%d+%) Unstage this hunk [^?]+%?]] do print(("%q"):format(x)) end'
 
-#[derive(Copy, Clone, Debug, parse_display::Display, parse_display::FromStr)]
-#[display(style = "snake_case")]
-enum Mode {
-    Initial,
-    RebaseTodo,
-    HunkSplit,
+/// What the hunks come from.
+#[derive(Clone, Debug)]
+enum Target {
+    Commit(String),
+    /// `git diff HEAD`.
+    WorkTree,
+    /// HEAD, split where it is instead of in a new rebase.
+    InPlace,
+    Stash(String),
+}
+
+/// `on` or `off`, whichever `value` says. The arguments for `hunk-split`
+/// spell out both, so it doesn't fall back on config the user overrode.
+fn switch(value: bool, on: &str, off: &str) -> String {
+    if value { on } else { off }.to_string()
+}
+
+/// Options of both `split` and `absorb`.
+#[derive(Clone, Debug)]
+struct CommonOptions {
+    /// Lines of context around each hunk.
+    context: usize,
+    reset_author: bool,
+    commit_options: CommitOptions,
+}
+
+impl CommonOptions {
+    /// The options as arguments for `hunk-split`.
+    fn to_args(&self) -> Vec<String> {
+        let mut args = vec!["--unified".to_string(), self.context.to_string()];
+        args.push(switch(
+            self.reset_author,
+            "--reset-author",
+            "--no-reset-author",
+        ));
+        match &self.commit_options.sign {
+            Sign::Default => {}
+            Sign::Key(None) => args.push("--gpg-sign".to_string()),
+            Sign::Key(Some(key)) => args.extend(["--sign-with".to_string(), key.clone()]),
+            Sign::Off => args.push("--no-gpg-sign".to_string()),
+        }
+        args.push(switch(
+            self.commit_options.no_verify,
+            "--no-verify",
+            "--verify",
+        ));
+        args
+    }
 }
 
-impl Default for Mode {
-    fn default() -> Self {
-        Self::Initial
+/// Options for the messages of a split.
+#[derive(Clone, Debug)]
+struct MessageOptions {
+    subject_template: Option<String>,
+    split_from: bool,
+}
+
+impl MessageOptions {
+    fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(template) = &self.subject_template {
+            args.extend(["--subject-template".to_string(), template.clone()]);
+        }
+        args.push(switch(self.split_from, "--split-from", "--no-split-from"));
+        args
+    }
+
+    /// The format for a split of `source`.
+    fn format(self, source: &str) -> save::MessageFormat {
+        save::MessageFormat {
+            subject_template: self.subject_template,
+            split_from: self.split_from.then(|| source.to_string()),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 enum Opts {
-    Initial { commit: String },
-    RebaseTodo { commit: String, todo: PathBuf },
-    HunkSplit { commit: String },
+    Split {
+        target: Target,
+        common: CommonOptions,
+        message: MessageOptions,
+        no_checkout: bool,
+        /// The upstream of the rebase, instead of the split commit.
+        base: Option<String>,
+        /// Where the rebase puts the commits, instead of where they are.
+        onto: Option<String>,
+    },
+    Absorb {
+        commit: String,
+        common: CommonOptions,
+    },
+    Restore,
+    Resume,
+    /// Run by `git rebase` as the sequence editor, to run `exec` first, or
+    /// right after picking `after`.
+    RebaseTodo {
+        exec: String,
+        after: Option<String>,
+        todo: PathBuf,
+    },
+    /// Run by `git rebase` once the commit to split is checked out.
+    HunkSplit {
+        commit: String,
+        common: CommonOptions,
+        message: MessageOptions,
+        /// Resuming: the revert is done already.
+        reverted: bool,
+        /// Split with `--in-place` rather than in a rebase of its own.
+        in_place: bool,
+    },
 }
 
 #[macro_export]
@@ -63,79 +169,327 @@ macro_rules! env_vars {
 }
 
 env_vars! {
-    @ENV_TARGET_COMMIT;
     // @ENV_MERGE_BASE;
-    @ENV_MODE;
     @ENV_CONTEXT_SIZE;
+    @ENV_THEME;
+    @ENV_BACKEND;
     GIT_SEQUENCE_EDITOR;
 }
 
-fn initial_opts() -> Opts {
-    use bpaf::*;
-    // let base = short('b')
-    //     .long("base")
-    //     .help("Merge base")
-    //     .argument("BASE")
-    //     .fallback("origin/master".to_string());
+/// `git config kgit-split.<key>`, the default of the matching option.
+fn config(key: &str) -> Option<String> {
+    get_output(git().args(["config", "--get", &format!("kgit-split.{key}")]))
+        .ok()
+        .map(|value| value.trim_end().to_string())
+}
+
+fn config_bool(key: &str) -> Option<bool> {
+    let value = get_output(git().args([
+        "config",
+        "--type=bool",
+        "--get",
+        &format!("kgit-split.{key}"),
+    ]))
+    .ok()?;
+    Some(value.trim_end() == "true")
+}
 
-    let commit = positional("TARGET_COMMIT").parse(|s| rev_parse(&s));
+/// `on` or `off`, if either is given, for switches that `kgit-split.*` can
+/// turn on by default.
+fn toggle(on: bpaf::Named, off: bpaf::Named) -> bpaf::Parser<Option<bool>> {
+    on.req_flag(true).or_else(off.req_flag(false)).optional()
+}
 
-    let parser = construct!(Opts::Initial { commit });
+fn common_options() -> bpaf::Parser<CommonOptions> {
+    use bpaf::*;
+    let context = short('U')
+        .long("unified")
+        .help("Lines of context around each hunk [kgit-split.unified]")
+        .argument("N")
+        .from_str::<usize>()
+        .fallback_with(|| -> Result<usize> {
+            match config("unified") {
+                Some(n) => Ok(n.parse()?),
+                None => Ok(context_size()),
+            }
+        });
+    let reset_author = toggle(
+        long("reset-author")
+            .help("Make the current user the author of the new commits, instead of the original author [kgit-split.resetAuthor]"),
+        long("no-reset-author").help("Keep the original author, even if kgit-split.resetAuthor is set"),
+    )
+    .map(|on| on.unwrap_or_else(|| config_bool("resetAuthor").unwrap_or(false)));
 
-    Info::default()
-        .descr("Split a commit")
-        .for_parser(parser)
-        .run()
+    let sign = short('S')
+        .long("gpg-sign")
+        .help("Sign the new commits with the default key [kgit-split.gpgSign]")
+        .req_flag(Sign::Key(None))
+        .or_else(
+            long("sign-with")
+                .help("Sign the new commits with this key [kgit-split.signWith]")
+                .argument("KEYID")
+                .map(|key| Sign::Key(Some(key))),
+        )
+        .or_else(
+            long("no-gpg-sign")
+                .help("Don't sign the new commits, even if commit.gpgSign is set")
+                .req_flag(Sign::Off),
+        )
+        .fallback_with(|| -> Result<Sign> {
+            Ok(match (config("signWith"), config_bool("gpgSign")) {
+                (Some(key), _) => Sign::Key(Some(key)),
+                (None, Some(true)) => Sign::Key(None),
+                (None, Some(false)) => Sign::Off,
+                (None, None) => Sign::Default,
+            })
+        });
+    let no_verify = toggle(
+        short('n')
+            .long("no-verify")
+            .help("Skip the pre-commit and commit-msg hooks [kgit-split.noVerify]"),
+        long("verify").help("Run the hooks, even if kgit-split.noVerify is set"),
+    )
+    .map(|on| on.unwrap_or_else(|| config_bool("noVerify").unwrap_or(false)));
+    let commit_options = construct!(CommitOptions { sign, no_verify });
+
+    construct!(CommonOptions {
+        context,
+        reset_author,
+        commit_options
+    })
 }
 
-fn rebase_todo_opts() -> Opts {
+fn message_options() -> bpaf::Parser<MessageOptions> {
     use bpaf::*;
-    let todo = positional("REBASE_TODO")
-        .from_str::<PathBuf>()
-        .guard(|f| f.exists(), "Path must exist");
-    let commit = long("commit")
-        .argument("COMMIT")
-        .fallback_with(|| std::env::var(ENV_TARGET_COMMIT));
-    let parser = construct!(Opts::RebaseTodo { commit, todo });
-    Info::default().for_parser(parser).run()
+    let subject_template = long("subject-template")
+        .help("Subject of each new commit, e.g. \"{subject} (part {i}/{n})\" [kgit-split.subjectTemplate]")
+        .argument("TEMPLATE")
+        .optional()
+        .map(|template| template.or_else(|| config("subjectTemplate")));
+    let split_from = toggle(
+        long("split-from")
+            .help("Add a \"Split-from: <sha>\" trailer to the new commits [kgit-split.splitFrom]"),
+        long("no-split-from").help("Don't add the trailer, even if kgit-split.splitFrom is set"),
+    )
+    .map(|on| on.unwrap_or_else(|| config_bool("splitFrom").unwrap_or(false)));
+    construct!(MessageOptions {
+        subject_template,
+        split_from
+    })
 }
 
-fn hunk_split_opts() -> Opts {
+fn split_opts() -> bpaf::Parser<Opts> {
     use bpaf::*;
-    let commit = long("commit").argument("COMMIT");
-    let parser = construct!(Opts::HunkSplit { commit });
-    Info::default().for_parser(parser).run()
+    let common = common_options();
+    let message = message_options();
+    let no_checkout = toggle(
+        long("no-checkout")
+            .help("Split without a rebase, leaving the working tree and index as they are; commit hooks don't run [kgit-split.noCheckout]"),
+        long("checkout").help("Split with a rebase, even if kgit-split.noCheckout is set"),
+    );
+    let base = long("base")
+        .help("Rebase from BASE rather than from the split commit, replaying the commits after it too [kgit-split.base]")
+        .argument("BASE")
+        .map(Some)
+        .or_else(long("no-base").help("Ignore kgit-split.base").req_flag(None))
+        .optional();
+    let onto = long("onto")
+        .help("Move the split commit and the ones after it onto NEWBASE [kgit-split.onto]")
+        .argument("NEWBASE")
+        .map(Some)
+        .or_else(
+            long("no-onto")
+                .help("Ignore kgit-split.onto")
+                .req_flag(None),
+        )
+        .optional();
+    let commit = positional("TARGET_COMMIT")
+        .parse(|s| rev_parse(&s))
+        .map(Target::Commit);
+    let worktree = short('w')
+        .long("worktree")
+        .help("Split the uncommitted changes into commits on top of HEAD instead")
+        .req_flag(Target::WorkTree);
+    let stash = long("stash")
+        .help("Split a stash entry into commits on top of HEAD, dropping it afterwards")
+        .argument("STASH")
+        .map(Target::Stash);
+    let in_place = short('i')
+        .long("in-place")
+        .help("Split HEAD where it is, e.g. while stopped at an `edit` in a rebase, without starting a rebase")
+        .req_flag(Target::InPlace);
+    let target = worktree.or_else(stash).or_else(in_place).or_else(commit);
+    construct!(common, message, no_checkout, base, onto, target).map(
+        |(common, message, no_checkout, base, onto, target)| {
+            // The config only fills in what works with the target and the
+            // options that were given, so that it never makes a split fail.
+            let defaults = matches!(target, Target::Commit(_))
+                && session::operation_in_progress().ok().flatten().is_none();
+            let rebase_options = matches!(base, Some(Some(_))) || matches!(onto, Some(Some(_)));
+            let no_checkout = no_checkout.unwrap_or_else(|| {
+                defaults && !rebase_options && config_bool("noCheckout").unwrap_or(false)
+            });
+            let or_config = |option: Option<Option<String>>, key| {
+                option.unwrap_or_else(|| (defaults && !no_checkout).then(|| config(key)).flatten())
+            };
+            Opts::Split {
+                common,
+                message,
+                no_checkout,
+                base: or_config(base, "base"),
+                onto: or_config(onto, "onto"),
+                target,
+            }
+        },
+    )
 }
 
 fn opts() -> Opts {
-    // let mode: Parser<Mode> = long("mode")
-    //     .argument("MODE")
-    //     .fallback_with(|| std::env::var(ENV_MODE))
-    //     .from_str::<Mode>()
-    //     .default()
-    //     .hide();
-    // let mode = Info::default().for_parser(mode).run();
-    let mode = std::env::var(ENV_MODE)
-        .map(|m| m.parse::<Mode>().with_context(|| format!("{m:?}")).unwrap())
-        .unwrap_or_default();
-    match mode {
-        Mode::Initial => initial_opts(),
-        Mode::RebaseTodo => rebase_todo_opts(),
-        Mode::HunkSplit => hunk_split_opts(),
+    use bpaf::*;
+    let split = command(
+        "split",
+        Some("Split a commit, the uncommitted changes or a stash entry into several commits"),
+        Info::default()
+            .descr("Split a commit, the uncommitted changes or a stash entry into several commits")
+            .for_parser(split_opts()),
+    );
+
+    let absorb = {
+        let common = common_options();
+        let commit = positional("COMMIT").parse(|s| rev_parse(&s));
+        let parser = construct!(Opts::Absorb { common, commit });
+        command(
+            "absorb",
+            Some("Turn a commit's hunks into fixups for the commits that last touched their lines"),
+            Info::default()
+                .descr("Turn a commit's hunks into fixups for the commits that last touched their lines, and squash them in")
+                .for_parser(parser),
+        )
+    };
+
+    let restore = command(
+        "restore",
+        Some("Put HEAD back where it was before the last split"),
+        Info::default()
+            .descr("Abort a split that is still running, or put HEAD back where it was before the last one, keeping the working tree")
+            .for_parser(Parser::pure(Opts::Restore)),
+    );
+
+    let resume = command(
+        "resume",
+        Some("Pick a split back up after it stopped"),
+        Info::default()
+            .descr("Reopen the hunks of a split whose commits failed, or continue its rebase after a conflict")
+            .for_parser(Parser::pure(Opts::Resume)),
+    );
+
+    let rebase_todo = {
+        let exec = long("exec").argument("COMMAND");
+        let after = long("after").argument("COMMIT").optional();
+        let todo = positional("REBASE_TODO")
+            .from_str::<PathBuf>()
+            .guard(|f| f.exists(), "Path must exist");
+        let parser = construct!(Opts::RebaseTodo { exec, after, todo });
+        command(
+            "rebase-todo",
+            None::<String>,
+            Info::default().for_parser(parser),
+        )
+        .hide()
+    };
+
+    let hunk_split = {
+        let commit = long("commit").argument("COMMIT");
+        let common = common_options();
+        let message = message_options();
+        let reverted = long("reverted").switch();
+        let in_place = long("in-place").switch();
+        let parser = construct!(Opts::HunkSplit {
+            commit,
+            common,
+            message,
+            reverted,
+            in_place
+        });
+        command(
+            "hunk-split",
+            None::<String>,
+            Info::default().for_parser(parser),
+        )
+        .hide()
+    };
+
+    let parser = split
+        .or_else(absorb)
+        .or_else(restore)
+        .or_else(resume)
+        .or_else(rebase_todo)
+        .or_else(hunk_split);
+
+    // Without a subcommand, the arguments are those of `split`. A hidden
+    // fallback parser would compete with `split` itself, so insert it.
+    const COMMANDS: [&str; 8] = [
+        "split",
+        "absorb",
+        "restore",
+        "resume",
+        "rebase-todo",
+        "hunk-split",
+        "-h",
+        "--help",
+    ];
+    let mut args = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if args
+        .first()
+        .is_some_and(|arg| !COMMANDS.contains(&arg.as_str()))
+    {
+        args.insert(0, "split".to_string());
+    }
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match Info::default()
+        .descr("Split git commits hunk by hunk")
+        .for_parser(parser)
+        .run_inner(Args::from(args.as_slice()))
+    {
+        Ok(opts) => opts,
+        Err(ParseFailure::Stdout(msg)) => {
+            println!("{msg}");
+            std::process::exit(0);
+        }
+        Err(ParseFailure::Stderr(msg)) => {
+            eprintln!("{msg}");
+            std::process::exit(1);
+        }
     }
 }
 
+/// Lines of context around each hunk, from `KGIT_SPLIT_CONTEXT_SIZE`.
+fn context_size() -> usize {
+    std::env::var(ENV_CONTEXT_SIZE)
+        .map_err(|err| anyhow::anyhow!("{err:?}"))
+        .and_then(|s| Ok(s.parse::<usize>()?))
+        .unwrap_or(1)
+}
+
 fn vec_to_utf8(s: Vec<u8>) -> String {
     String::from_utf8(s).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
 }
 
+/// Quotes `arg` for `sh`.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
 fn git() -> Command {
     Command::new("git")
 }
 
 fn sh(command: &str) -> Command {
     let mut cmd = Command::new("sh");
-    cmd.args(&["-c", command]);
+    cmd.args(["-c", command]);
     cmd
 }
 
@@ -197,31 +551,125 @@ fn rev_parse(input: impl AsRef<str>) -> Result<String> {
     Ok(s)
 }
 
+/// The path of `name` inside the git directory.
+fn git_path(name: &str) -> Result<PathBuf> {
+    Ok(PathBuf::from(
+        get_output(git().args(["rev-parse", "--git-path", name]))?.trim_end(),
+    ))
+}
+
 fn render_hunk(hunk: &str, max_lines: usize) -> impl std::fmt::Display + '_ {
+    let theme = theme();
     FmtFn(move |f| {
         for line in hunk.split('\n').take(max_lines) {
-            if let Some(line) = line.strip_prefix("+") {
-                writeln!(
-                    f,
-                    "{color}+{line}{reset}",
-                    color = termion::color::Fg(termion::color::Green),
-                    reset = termion::color::Fg(termion::color::Reset),
-                )?;
-            } else if let Some(line) = line.strip_prefix("-") {
-                writeln!(
-                    f,
-                    "{color}-{line}{reset}",
-                    color = termion::color::Fg(termion::color::Red),
-                    reset = termion::color::Fg(termion::color::Reset),
-                )?;
+            let style = if line.starts_with("@@") {
+                Some(theme.frag)
+            } else if line.starts_with('+') {
+                Some(theme.added)
+            } else if line.starts_with('-') {
+                Some(theme.removed)
             } else {
-                writeln!(f, "{line}")?;
+                None
+            };
+            match style {
+                Some(style) => writeln!(f, "{}{line}{}", theme.paint(style), theme.reset())?,
+                None => writeln!(f, "{line}")?,
             }
         }
         Ok(())
     })
 }
 
+/// Renders a hunk as two columns, old on the left and new on the right, each
+/// with line numbers. Removed and added runs are paired up line by line.
+fn render_hunk_side_by_side(hunk: &str, width: usize, max_lines: usize) -> String {
+    let theme = theme();
+    let mut lines = hunk.split('\n');
+    let header = lines.next().unwrap_or_default();
+    let Some(range) = HunkHeader::parse(header) else {
+        return render_hunk(hunk, max_lines).to_string();
+    };
+    let number_width = (range.old_end().max(range.new_start + range.new_len))
+        .to_string()
+        .len();
+    let column_width = width.saturating_sub(3) / 2;
+    let text_width = column_width.saturating_sub(number_width + 1);
+    // Fits a line's text (without the +/-/space) into exactly `text_width`.
+    let cell = |text: &str| {
+        let mut cell = String::new();
+        for c in text.chars() {
+            match c {
+                '\t' => cell.extend(std::iter::repeat_n(' ', 4 - cell.len() % 4)),
+                '\r' => {}
+                c => cell.push(c),
+            }
+        }
+        let mut cell = cell.chars().take(text_width).collect::<String>();
+        let pad = text_width - cell.chars().count();
+        cell.extend(std::iter::repeat_n(' ', pad));
+        cell
+    };
+    let side = |number: Option<usize>, text: Option<&str>, style| match (number, text) {
+        (Some(number), Some(text)) => format!(
+            "{faint}{number:>number_width$}{reset} {color}{text}{reset}",
+            faint = theme.paint(theme.faint),
+            color = theme.paint(style),
+            text = cell(text),
+            reset = theme.reset(),
+        ),
+        _ => " ".repeat(column_width),
+    };
+
+    // (old text, new text, changed), None where one side has fewer lines.
+    type Row<'a> = (Option<&'a str>, Option<&'a str>, bool);
+    fn flush<'a>(removed: &mut Vec<&'a str>, added: &mut Vec<&'a str>, rows: &mut Vec<Row<'a>>) {
+        for i in 0..removed.len().max(added.len()) {
+            rows.push((removed.get(i).copied(), added.get(i).copied(), true));
+        }
+        removed.clear();
+        added.clear();
+    }
+    let mut rows = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for line in lines {
+        match line.chars().next() {
+            Some('-') => removed.push(&line[1..]),
+            Some('+') => added.push(&line[1..]),
+            // "\ No newline at end of file"
+            Some('\\') => {}
+            _ => {
+                flush(&mut removed, &mut added, &mut rows);
+                let text = line.get(1..).unwrap_or_default();
+                rows.push((Some(text), Some(text), false));
+            }
+        }
+    }
+    flush(&mut removed, &mut added, &mut rows);
+
+    let separator = format!(" {}|{} ", theme.paint(theme.faint), theme.reset());
+    let (mut old, mut new) = (range.old_start, range.new_start);
+    let mut rendered = vec![format!(
+        "{}{header}{}",
+        theme.paint(theme.frag),
+        theme.reset()
+    )];
+    for (left, right, changed) in rows {
+        let (left_style, right_style) = match changed {
+            true => (theme.removed, theme.added),
+            false => (Style::PLAIN, Style::PLAIN),
+        };
+        rendered.push(format!(
+            "{}{separator}{}",
+            side(left.map(|_| old), left, left_style),
+            side(right.map(|_| new), right, right_style),
+        ));
+        old += left.is_some() as usize;
+        new += right.is_some() as usize;
+    }
+    rendered.truncate(max_lines);
+    rendered.into_iter().map(|line| line + "\n").collect()
+}
+
 fn edit(content: impl AsRef<[u8]>) -> Result<String> {
     let editor = std::env::var("EDITOR").or_else(|_| std::env::var("VISUAL"))?;
     let path: PathBuf = std::env::temp_dir().join(".gitsplit_edit");
@@ -240,676 +688,373 @@ fn edit(content: impl AsRef<[u8]>) -> Result<String> {
 // initiating a rebase at all.
 // Then do the revert commit and then create all the hunks afterwards, except
 // without the reverse flag.
+/// Splits `commit`, which the rebase has just picked, by reverting it
+/// (unless `reverted`, when resuming) and creating the new commits on top.
+/// The rebase is aborted if the user quits, unless splitting `in_place`
+/// outside of one started for the split: then HEAD goes back to `commit`.
+fn hunk_split(
+    commit: &str,
+    common: CommonOptions,
+    message: MessageOptions,
+    reverted: bool,
+    in_place: bool,
+) -> Result<()> {
+    log::debug!("hunk splitting {commit:?}");
+    // The commit as the rebase picked it, which is `commit` itself unless
+    // the split moves it with `--onto`.
+    let picked = rev_parse(if reverted { "HEAD~" } else { "HEAD" })?;
+    let diff_context_size = common.context;
+    let raw_hunks = backend::open(
+        diff_context_size,
+        backend::ApplyTo::WorkTree,
+        &Default::default(),
+    )
+    .diff(&format!("{picked}~"), Some(&picked))?;
+    let original_commit_message = get_output(git().args([
+        "log",
+        "--reverse",
+        "--pretty=format:%B",
+        &format!("{commit}~..{commit}"),
+    ]))?;
+    log::debug!("{raw_hunks:?}\n\n");
+    let files = parse_diff(&raw_hunks)?;
+    log::debug!("After parsing {files:#?}");
+    // for (header, hunks) in files.iter_mut() {
+    //     hunks.extend(std::mem::take(&mut hunks).into_iter()
+    //                  .flat_map(|hunk| {
+    //                      if hunk.split('\n').any(|line| line.starts_with("+")) &&
+    //                      hunk.split('\n').any(|line| line.starts_with("-")) {
+    //                          let mut add = Vec::new();
+    //                          let mut rem = Vec::new()kkkkkk
+    //                          for line in hunk.split('\n') {
+    //                          }
+    //                      }
+    //                  }))
+    // }
+    let original = save::Original::read(commit, common.reset_author)?;
+    if !reverted {
+        get_output(git().args(["revert", "--no-commit", &picked]))?;
+        // Hooks are for the commits the user writes, not this one.
+        get_output(git().args([
+            "commit",
+            "--no-verify",
+            "-m",
+            &format!("@split Revert {commit}: {original_commit_message}"),
+        ]))?;
+    }
+    let ui_state = {
+        let mut ui_state = UiState {
+            files,
+            message_format: message.format(commit),
+            source_commit: Some(picked.clone()),
+            commit_options: common.commit_options,
+            ..Default::default()
+        };
+        ui_state.insert_message(
+            '0',
+            CommitInfo {
+                commit_message: original_commit_message.clone(),
+            },
+        );
+        ui_state
+            .hunk_commits
+            .extend((0..ui_state.hunk_count()).map(|_| Some('0')));
+        ui::run(ui_state, move |plan, commit_options, progress| {
+            let backend = backend::open(
+                diff_context_size,
+                backend::ApplyTo::WorkTree,
+                commit_options,
+            );
+            save::save(&plan, &*backend, &original, progress)
+        })?
+    };
+    // TODO repeatedly edit a hunk and produce diffs
+    // until the user edits an empty file.
+    // That way can split a hunk even further
+    if ui_state.saved {
+        // Sanity check
+        if ui_state.all_hunks_assigned() {
+            if let Err(err) = get_output(git().args(["diff", "--check", &picked])) {
+                log::error!(
+                    "Failed sanity check diff, even though all hunks were assigned.\n\
+                    Error: {err:?}"
+                );
+                return Err(err);
+            }
+        }
+        // get_output(
+        //     git().args(&["rebase", "--continue"]),
+        // )?;
+        if in_place {
+            session::finish_in_place()?;
+            if session::operation_in_progress()? == Some("rebase") {
+                eprintln!("Run `git rebase --continue` to go on with the rebase");
+            }
+        }
+    } else if let (Some(err), false) = (&ui_state.save_error, ui_state.dont_save) {
+        anyhow::bail!(
+            "Failed to save the commits:\n{err}\n\n\
+             Run `git split resume` to try again, or `git split restore` to go back to {commit}"
+        );
+    } else if in_place {
+        // Quitting gives up on the split, even after a failed save.
+        get_output(git().args(["reset", "-q", "--hard", &picked]))?;
+        session::finish_in_place()?;
+    } else {
+        get_output(git().args(["rebase", "--abort"]))?;
+        // anyhow::bail!("Not all hunks assigned");
+    }
+    Ok(())
+}
+
+/// The `hunk-split` command that splits `commit`. Everything the split needs
+/// travels on it, so that `resume` can run it again.
+fn hunk_split_command(
+    commit: &str,
+    common: &CommonOptions,
+    message: &MessageOptions,
+) -> Result<String> {
+    let exe = shell_quote(&std::env::current_exe()?.display().to_string());
+    Ok(
+        std::iter::once(format!("{exe} hunk-split --commit {commit}"))
+            .chain(
+                common
+                    .to_args()
+                    .into_iter()
+                    .chain(message.to_args())
+                    .map(|arg| shell_quote(&arg)),
+            )
+            .join(" "),
+    )
+}
+
+/// Refuses what can't be done in the middle of a merge, cherry-pick, revert
+/// or rebase. A rebase stopped at the commit to split, e.g. on an `edit`,
+/// gets it split in place instead of in a nested rebase.
+fn check_in_progress(opts: Opts) -> Result<Opts> {
+    if !matches!(opts, Opts::Split { .. } | Opts::Absorb { .. }) {
+        return Ok(opts);
+    }
+    match session::operation_in_progress()? {
+        None => Ok(opts),
+        Some("rebase") => match opts {
+            Opts::Absorb { .. } => {
+                anyhow::bail!("A rebase is in progress; finish or abort it before absorbing")
+            }
+            Opts::Split {
+                no_checkout: true, ..
+            } => anyhow::bail!("--no-checkout can't be used while a rebase is in progress"),
+            Opts::Split { base: Some(_), .. } | Opts::Split { onto: Some(_), .. } => {
+                anyhow::bail!("--base and --onto can't be used while a rebase is in progress")
+            }
+            Opts::Split {
+                target: Target::Commit(commit),
+                common,
+                message,
+                no_checkout,
+                base,
+                onto,
+            } => {
+                anyhow::ensure!(
+                    rev_parse(&commit)? == rev_parse("HEAD")?,
+                    "A rebase is in progress; stop it at {commit} (mark it `edit`) \
+                     and run `git split --in-place`, or finish the rebase first"
+                );
+                eprintln!("A rebase is stopped at {commit}; splitting it in place");
+                Ok(Opts::Split {
+                    target: Target::InPlace,
+                    common,
+                    message,
+                    no_checkout,
+                    base,
+                    onto,
+                })
+            }
+            opts => Ok(opts),
+        },
+        Some(operation) => {
+            anyhow::bail!("A {operation} is in progress; finish or abort it before splitting")
+        }
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::init();
-    let opts = opts();
+    let opts = check_in_progress(opts())?;
     match opts {
-        Opts::Initial { commit } => {
-            // let prev_commit = rev_parse(&format!("{commit}~"))
-            //     .with_context(|| format!("Failed to find previous commit of {commit:?}. \
-            //                              This can happen if the previous commit is the first commit."))?;
-            // let rebase_commit = &prev_commit;
-            let rebase_commit = &commit;
-            let exe = std::env::current_exe()?.display().to_string();
+        Opts::Split {
+            target,
+            no_checkout,
+            base,
+            onto,
+            ..
+        } if (base.is_some() || onto.is_some())
+            && (no_checkout || !matches!(target, Target::Commit(_))) =>
+        {
+            anyhow::bail!("--base and --onto only work when splitting a commit with a rebase");
+        }
+        Opts::Split {
+            target: Target::WorkTree | Target::Stash(_) | Target::InPlace,
+            no_checkout: true,
+            ..
+        } => {
+            anyhow::bail!("--no-checkout only works when splitting a commit");
+        }
+        Opts::Split {
+            target: Target::Commit(commit),
+            no_checkout: true,
+            common,
+            message,
+            ..
+        } => {
+            session::save_orig_head()?;
+            detached::split_detached(
+                &commit,
+                common.context,
+                common.reset_author,
+                &common.commit_options,
+                message.format(&commit),
+            )?;
+        }
+        Opts::Split {
+            target: Target::WorkTree,
+            common,
+            message,
+            ..
+        } => {
+            let message_format = save::MessageFormat {
+                subject_template: message.subject_template,
+                split_from: None,
+            };
+            session::save_orig_head()?;
+            worktree::split_worktree(common.context, &common.commit_options, message_format)?;
+        }
+        Opts::Split {
+            target: Target::Stash(stash),
+            common,
+            message,
+            ..
+        } => {
+            let message_format = message.format(&rev_parse(&stash)?);
+            session::save_orig_head()?;
+            worktree::split_stash(
+                &stash,
+                common.context,
+                &common.commit_options,
+                message_format,
+            )?;
+        }
+        Opts::Split {
+            target: Target::InPlace,
+            common,
+            message,
+            ..
+        } => {
+            rev_parse("HEAD~").context("Can't split a root commit")?;
+            anyhow::ensure!(
+                git().args(["diff", "--quiet", "HEAD"]).status()?.success(),
+                "Splitting in place needs a clean working tree and index"
+            );
+            let head = rev_parse("HEAD")?;
+            let exec = hunk_split_command(&head, &common, &message)?;
+            session::save_orig_head()?;
+            session::start_in_place(&head, &format!("{exec} --in-place"))?;
+            hunk_split(&head, common, message, false, true)?;
+        }
+        Opts::Absorb { commit, common } => {
+            session::save_orig_head()?;
+            absorb::absorb(
+                &commit,
+                common.context,
+                common.reset_author,
+                &common.commit_options,
+            )?;
+        }
+        Opts::Split {
+            target: Target::Commit(commit),
+            no_checkout: false,
+            common,
+            message,
+            base,
+            onto,
+        } => {
+            // Without `--base` or `--onto`, the rebase starts at the split
+            // commit and splits it first; otherwise it picks it on the way.
+            let (rebase_commit, after) = match (base, &onto) {
+                (None, None) => (commit.clone(), None),
+                (Some(base), _) => {
+                    let base = rev_parse(&base)?;
+                    anyhow::ensure!(
+                        !git()
+                            .args(["merge-base", "--is-ancestor", &commit, &base])
+                            .status()?
+                            .success(),
+                        "{commit} is already part of the base {base}"
+                    );
+                    (base, Some(commit.clone()))
+                }
+                (None, Some(_)) => (
+                    rev_parse(format!("{commit}~"))
+                        .context("Can't move the root commit with --onto")?,
+                    Some(commit.clone()),
+                ),
+            };
+            let exe = shell_quote(&std::env::current_exe()?.display().to_string());
+            let exec = hunk_split_command(&commit, &common, &message)?;
+            session::save_orig_head()?;
+            let onto = onto.map(rev_parse).transpose()?;
+            let after = after
+                .map(|after| format!(" --after {after}"))
+                .unwrap_or_default();
             anyhow::ensure!(git()
-                .args(&["rebase", "-i", rebase_commit])
-                .env(GIT_SEQUENCE_EDITOR, &exe)
-                .env(ENV_TARGET_COMMIT, &commit)
+                .args(["rebase", "-i"])
+                .args(onto.iter().flat_map(|onto| ["--onto", onto]))
+                .arg(&rebase_commit)
+                .env(
+                    GIT_SEQUENCE_EDITOR,
+                    format!("{exe} rebase-todo --exec {}{after}", shell_quote(&exec))
+                )
                 // .env(ENV_MERGE_BASE, &opts.base)
-                .env(ENV_MODE, Mode::RebaseTodo.to_string())
                 .status()?
                 .success());
         }
-        Opts::RebaseTodo { todo, commit } => {
+        Opts::Restore => session::restore()?,
+        Opts::Resume => session::resume()?,
+        Opts::RebaseTodo { todo, exec, after } => {
             let raw_todo = std::fs::read_to_string(&todo)?;
-            let rebase_commands = raw_todo
+            let mut rebase_commands = raw_todo
                 .split('\n')
                 .filter(|line| !(line.starts_with('#') || line.is_empty()))
+                .map(str::to_string)
                 .collect::<Vec<_>>();
-            let exe = std::env::current_exe()?.display().to_string();
-            log::debug!("{commit:?} {rebase_commands:#?}");
-            let hunk_mode = Mode::HunkSplit;
-            std::fs::write(
-                &todo,
-                &format!(
-                    "x env {ENV_MODE}={hunk_mode} {exe:?} --commit {commit:?}\n{}",
-                    rebase_commands.iter().format("\n")
-                ),
-            )
-            .with_context(|| format!("Failed to write to {todo:?}", todo = todo.display()))?;
-            // std::fs::write(&format!("x sh {ENV_MODE}={hunk_mode:?} {exe:?} {}",
-            // assert!(commit.starts_with(rebase_commands[0].split(' ').nth(1).unwrap()));
-        }
-        Opts::HunkSplit { commit } => {
-            log::debug!("hunk splitting {commit:?}");
-            let diff_context_size = std::env::var(ENV_CONTEXT_SIZE)
-                .map_err(|err| anyhow::anyhow!("{err:?}"))
-                .and_then(|s| Ok(s.parse::<usize>()?))
-                .unwrap_or(1);
-            let raw_hunks = get_output(git().args(&[
-                "diff",
-                "-p",
-                &format!("-U{diff_context_size}"),
-                &format!("{commit}~"),
-                &commit,
-            ]))?;
-            let original_commit_message = get_output(git().args(&[
-                "log",
-                "--reverse",
-                "--pretty=format:%B",
-                &format!("{commit}~..{commit}"),
-            ]))?;
-            log::debug!("{raw_hunks:?}\n\n");
-            let hunk_start_pat = Regex::new(r#"@@ \-\d+(?:,\d+)? \+\d+(?:,\d+)? @@"#)?;
-            let diff_start_pat = Regex::new(r#"diff .*"#)?;
-            let mut files = Vec::new();
-            {
-                enum ParseMode {
-                    Hunk,
-                    Header,
-                }
-                let mut lines = raw_hunks.split('\n');
-                let mut header = diff_start_pat
-                    .captures(lines.next().expect("diff was empty"))
-                    .expect("First line should be diff in diff")[0]
-                    .to_string();
-                let mut hunks = Vec::new();
-                let mut parse_mode = ParseMode::Header;
-
-                for line in lines {
-                    match parse_mode {
-                        ParseMode::Hunk => {
-                            if let Some(_cap) = diff_start_pat.captures(line) {
-                                files.push((
-                                    std::mem::take(&mut header),
-                                    std::mem::take(&mut hunks),
-                                ));
-                                header.push_str(line);
-                                parse_mode = ParseMode::Header;
-                            } else if let Some(_cap) = hunk_start_pat.captures(line) {
-                                hunks.push(line.to_string());
-                                parse_mode = ParseMode::Hunk;
-                            } else {
-                                write!(hunks.last_mut().unwrap(), "\n{line}").unwrap();
-                                parse_mode = ParseMode::Hunk;
-                            }
-                        }
-                        ParseMode::Header => {
-                            if let Some(_cap) = diff_start_pat.captures(line) {
-                                files.push((
-                                    std::mem::take(&mut header),
-                                    std::mem::take(&mut hunks),
-                                ));
-                                header.push_str(line);
-                                parse_mode = ParseMode::Header;
-                            } else if let Some(_cap) = hunk_start_pat.captures(line) {
-                                hunks.push(line.to_string());
-                                parse_mode = ParseMode::Hunk;
-                            } else {
-                                write!(header, "\n{line}").unwrap();
-                                parse_mode = ParseMode::Header;
-                            }
-                        }
-                    }
-                }
-                if !header.is_empty() {
-                    files.push((std::mem::take(&mut header), std::mem::take(&mut hunks)));
-                }
-            }
-            log::debug!("After parsing {files:#?}");
-            // for (header, hunks) in files.iter_mut() {
-            //     hunks.extend(std::mem::take(&mut hunks).into_iter()
-            //                  .flat_map(|hunk| {
-            //                      if hunk.split('\n').any(|line| line.starts_with("+")) &&
-            //                      hunk.split('\n').any(|line| line.starts_with("-")) {
-            //                          let mut add = Vec::new();
-            //                          let mut rem = Vec::new()kkkkkk
-            //                          for line in hunk.split('\n') {
-            //                          }
-            //                      }
-            //                  }))
-            // }
-            get_output(git().args(&["revert", "--no-commit", &commit]))?;
-            get_output(git().args(&[
-                "commit",
-                "-m",
-                &format!("@split Revert {commit}: {original_commit_message}"),
-            ]))?;
-            type CommitId = char;
-            #[derive(Hash, Debug)]
-            enum UiMode {
-                Editing {
-                    commit: CommitId,
-                    message: Vec<char>,
-                    assign_to_hunk: Option<usize>,
-                },
-                Viewing {
-                    active_hunk: usize,
-                },
-                WaitingToEdit,
-            }
-
-            impl Default for UiMode {
-                fn default() -> Self {
-                    Self::Viewing { active_hunk: 0 }
-                }
-            }
-
-            use std::collections::BTreeMap;
-            #[derive(Hash)]
-            struct CommitInfo {
-                commit_message: String,
-            }
-
-            #[derive(Hash, Default)]
-            struct UiState {
-                dont_save: bool,
-                files: Vec<(String, Vec<String>)>,
-                force_redraw_gen: u64,
-                force_redraw_terminal_size: (u16, u16),
-                allow_partial: bool,
-                active_mode: UiMode,
-                previous_modes: Vec<UiMode>,
-                messages: BTreeMap<CommitId, CommitInfo>,
-                hunk_commits: Vec<Option<CommitId>>,
-            }
-
-            impl UiState {
-                pub fn push_mode(&mut self, mode: UiMode) {
-                    self.previous_modes
-                        .push(std::mem::replace(&mut self.active_mode, mode));
-                }
-
-                pub fn set_mode(&mut self, mode: UiMode) -> UiMode {
-                    std::mem::replace(&mut self.active_mode, mode)
-                }
-
-                pub fn pop_mode(&mut self) -> Option<UiMode> {
-                    if let Some(mode) = self.previous_modes.pop() {
-                        Some(self.set_mode(mode))
-                    } else {
-                        None
-                    }
-                }
-
-                pub fn all_hunks_assigned(&self) -> bool {
-                    self.hunk_commits.len() >= self.hunk_count()
-                        && self.hunk_commits.iter().all(|c| c.is_some())
-                }
-
-                pub fn should_save_commits(&self) -> bool {
-                    !self.dont_save && (self.allow_partial || self.all_hunks_assigned())
-                }
-
-                pub fn set_hunk_commit(&mut self, hunk_idx: usize, commit_id: CommitId) {
-                    if self.hunk_commits.len() <= hunk_idx {
-                        self.hunk_commits
-                            .resize_with(hunk_idx + 1, Default::default);
-                    }
-                    self.hunk_commits[hunk_idx] = Some(commit_id);
-                }
-
-                pub fn hunks(&self) -> impl Iterator<Item = ((usize, usize), (&str, &str))> {
-                    self.files
+            log::debug!("{exec:?} {rebase_commands:#?}");
+            let position = match &after {
+                None => 0,
+                Some(after) => {
+                    rebase_commands
                         .iter()
-                        .enumerate()
-                        .flat_map(|(file_id, (header, hunks))| {
-                            hunks.iter().enumerate().map(move |(hunk_id, hunk)| {
-                                ((file_id, hunk_id), (header.as_str(), hunk.as_str()))
-                            })
+                        .position(|line| {
+                            let mut words = line.split(' ');
+                            matches!(words.next(), Some("pick" | "p"))
+                                && words.next().is_some_and(|sha| after.starts_with(sha))
                         })
+                        .with_context(|| format!("The rebase doesn't pick {after}"))?
+                        + 1
                 }
-
-                pub fn get_hunk(&self, idx: usize) -> Option<((usize, usize), (&str, &str))> {
-                    self.hunks().nth(idx)
-                }
-
-                pub fn hunk_count(&self) -> usize {
-                    self.files.iter().map(|(_, hunks)| hunks.len()).sum()
-                }
-            }
-            use std::hash::Hash;
-            use termion::{input::TermRead, raw::IntoRawMode};
-            trait GetHash: Hash {
-                fn meow_hash(&self) -> u128 {
-                    meow_hash(None, &self)
-                }
-            }
-            impl<H: Hash> GetHash for H {}
-            let ui_state = {
-                let mut commit_colors_seq = [
-                    termion::color::Fg(termion::color::LightRed).to_string(),
-                    termion::color::Fg(termion::color::LightYellow).to_string(),
-                    termion::color::Fg(termion::color::LightBlue).to_string(),
-                    termion::color::Fg(termion::color::LightGreen).to_string(),
-                    termion::color::Fg(termion::color::LightCyan).to_string(),
-                    termion::color::Fg(termion::color::LightMagenta).to_string(),
-                    termion::color::Fg(termion::color::LightBlack).to_string(),
-                    termion::color::Fg(termion::color::LightWhite).to_string(),
-                    termion::color::Fg(termion::color::Blue).to_string(),
-                    termion::color::Fg(termion::color::Red).to_string(),
-                ]
-                .into_iter();
-                let mut commit_colors: BTreeMap<CommitId, Option<String>> = BTreeMap::new();
-                let stdin = std::io::stdin();
-                // let mut stdin = termion::async_stdin();
-                let mut ui_state: UiState = Default::default();
-                ui_state.files = files;
-                ui_state.messages.insert(
-                    '0',
-                    CommitInfo {
-                        commit_message: original_commit_message.clone(),
-                    },
-                );
-                ui_state
-                    .hunk_commits
-                    .extend((0..ui_state.hunk_count()).map(|_| Some('0')));
-                let mut prev_hash = 0;
-                let stdout = stdout();
-                let stdout = stdout.into_raw_mode()?;
-                let mut screen = termion::screen::AlternateScreen::from(stdout);
-                // let mut screen = stdout;
-                let mut keys = stdin.keys();
-                let mut draw_buffer = String::new();
-                let mut out_buffer = String::new();
-                'ui_loop: for gen in 1.. {
-                    ui_state.force_redraw_terminal_size =
-                        termion::terminal_size().unwrap_or_default();
-                    let should_redraw = {
-                        let hash = ui_state.meow_hash();
-                        if hash != prev_hash {
-                            prev_hash = hash;
-                            true
-                        } else {
-                            false
-                        }
-                    };
-                    if should_redraw {
-                        let terminal_height = ui_state.force_redraw_terminal_size.1;
-                        write!(
-                            draw_buffer,
-                            "{}{}",
-                            termion::cursor::Goto(1, 1),
-                            termion::clear::All
-                        )?;
-                        writeln!(
-                            draw_buffer,
-                            "{x}/{n} hunks assigned{partial}",
-                            x = ui_state.hunk_commits.iter().filter(|h| h.is_some()).count(),
-                            n = ui_state.hunk_count(),
-                            partial = ui_state
-                                .allow_partial
-                                .then(|| FmtFn(|f| {
-                                    write!(
-                                        f,
-                                        " {color}ALLOW PARTIAL{reset}",
-                                        color = termion::color::Fg(termion::color::Yellow),
-                                        reset = termion::color::Fg(termion::color::Reset),
-                                    )
-                                }))
-                                .or_display("")
-                        )?;
-                        for (id, CommitInfo { commit_message }) in ui_state.messages.iter() {
-                            writeln!(
-                                draw_buffer,
-                                "{color}{id}: {commit_message}{reset}",
-                                commit_message = commit_message.split('\n').next().unwrap(),
-                                color = commit_colors
-                                    .entry(*id)
-                                    .or_insert_with(|| commit_colors_seq.next())
-                                    .or_display(""),
-                                reset = termion::color::Fg(termion::color::Reset),
-                            )?;
-                        }
-                        match &ui_state.active_mode {
-                            UiMode::WaitingToEdit => {
-                                write!(draw_buffer, "Enter commit id to edit: ",)?;
-                            }
-                            UiMode::Editing {
-                                commit,
-                                message,
-                                assign_to_hunk: _,
-                            } => {
-                                write!(
-                                    draw_buffer,
-                                    "For {color}{commit}{reset}:\n{message}",
-                                    message = message.iter().format(""),
-                                    color = commit_colors
-                                        .entry(*commit)
-                                        .or_insert_with(|| commit_colors_seq.next())
-                                        .or_display(""),
-                                    reset = termion::color::Fg(termion::color::Reset),
-                                )?;
-                            }
-                            UiMode::Viewing { active_hunk } => {
-                                let ((_file_id, _hunk_id), (header, hunk)) =
-                                    ui_state.get_hunk(*active_hunk).unwrap();
-                                let commit =
-                                    ui_state.hunk_commits.get(*active_hunk).copied().flatten();
-                                let commit_message = commit
-                                    .map(|commit| {
-                                        let messages = &ui_state.messages;
-                                        FmtFn(move |f| {
-                                            write!(
-                                                f,
-                                                "[{commit}] {}",
-                                                messages[&commit]
-                                                    .commit_message
-                                                    .split('\n')
-                                                    .next()
-                                                    .unwrap()
-                                            )
-                                        })
-                                    })
-                                    .into_or_display("---");
-                                let header_line = header.split('\n').next().unwrap();
-                                writeln!(
-                                    draw_buffer,
-                                    "{active_hunk}/{n}: {color}{commit_message}{reset}\n{header_line}",
-                                    n = ui_state.hunk_count(),
-                                    color = commit.and_then(|commit| commit_colors
-                                        .entry(commit)
-                                        .or_insert_with(|| commit_colors_seq.next()).as_ref())
-                                        .into_or_display(""),
-                                    reset = termion::color::Fg(termion::color::Reset),
-                                )?;
-                                writeln!(
-                                    draw_buffer,
-                                    "{}",
-                                    render_hunk(
-                                        hunk,
-                                        (terminal_height as usize)
-                                            .saturating_sub(ui_state.messages.len() + 5)
-                                    )
-                                )?;
-                            }
-                        }
-
-                        for c in draw_buffer.drain(..) {
-                            if c == '\n' {
-                                out_buffer.push_str("\r\n");
-                            } else {
-                                out_buffer.push(c);
-                            }
-                        }
-
-                        screen.write_all(out_buffer.as_bytes())?;
-                        screen.flush()?;
-                    }
-                    let key = if let Some(key) = keys.next() {
-                        key?
-                    } else {
-                        break 'ui_loop;
-                    };
-                    match &ui_state.active_mode {
-                        UiMode::WaitingToEdit => {
-                            ui_state.pop_mode();
-                            match key {
-                                termion::event::Key::Char(commit @ '0'..='9') => {
-                                    let message = ui_state
-                                        .messages
-                                        .get(&commit)
-                                        .as_ref()
-                                        .map(|info| info.commit_message.as_str())
-                                        .unwrap_or("");
-                                    if let Ok(new_message) = edit(message) {
-                                        ui_state.messages.insert(
-                                            commit,
-                                            CommitInfo {
-                                                commit_message: new_message,
-                                            },
-                                        );
-                                    }
-                                    // let mode = UiMode::Editing {
-                                    //     commit: c,
-                                    //     message: ui_state
-                                    //         .messages
-                                    //         .get(&c)
-                                    //         .map(|info| info.commit_message.chars().collect_vec())
-                                    //         .unwrap_or_default(),
-                                    //     assign_to_hunk: None,
-                                    // };
-                                    // ui_state.push_mode(mode);
-                                }
-                                _ => (),
-                            }
-                        }
-                        UiMode::Editing { message, .. } => match key {
-                            termion::event::Key::Char(c) => match &mut ui_state.active_mode {
-                                UiMode::Editing {
-                                    ref mut message, ..
-                                } => {
-                                    message.push(c);
-                                }
-                                _ => unreachable!(),
-                            },
-                            termion::event::Key::Backspace => match &mut ui_state.active_mode {
-                                UiMode::Editing {
-                                    ref mut message, ..
-                                } => {
-                                    message.pop();
-                                }
-                                _ => unreachable!(),
-                            },
-                            termion::event::Key::Esc => {
-                                ui_state.pop_mode();
-                            }
-                            termion::event::Key::Ctrl('s') if !message.is_empty() => {
-                                match ui_state.pop_mode() {
-                                    Some(UiMode::Editing {
-                                        commit,
-                                        message,
-                                        assign_to_hunk,
-                                    }) => {
-                                        ui_state.messages.insert(
-                                            commit,
-                                            CommitInfo {
-                                                commit_message: String::from_iter(message),
-                                            },
-                                        );
-                                        if let Some(assign_to_hunk) = assign_to_hunk {
-                                            ui_state.set_hunk_commit(assign_to_hunk, commit);
-                                        }
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                            _ => (),
-                        },
-                        UiMode::Viewing { active_hunk } => {
-                            let active_hunk = *active_hunk;
-                            match key {
-                                termion::event::Key::Ctrl('f') => {
-                                    ui_state.allow_partial = !ui_state.allow_partial;
-                                }
-                                termion::event::Key::Ctrl('l') => {
-                                    ui_state.force_redraw_gen = gen;
-                                }
-                                // https://github.com/twaugh/patchutils/blob/master/src/rediff.c
-                                termion::event::Key::Ctrl('e') => {
-                                    let launched_editor = loop {
-                                        let ((file_id, _), (_, hunk_body)) =
-                                            ui_state.get_hunk(active_hunk).unwrap();
-                                        match edit(hunk_body) {
-                                            Ok(output) if output.trim().is_empty() => break true,
-                                            Ok(output) => {
-                                                ui_state.files[file_id].1.push(output);
-                                            }
-                                            Err(err) => {
-                                                log::error!("Failed to edit hunk body {err:?}");
-                                                break false;
-                                            }
-                                        }
-                                    };
-                                    if launched_editor {
-                                        // Works because we're always appending the
-                                        // hunks after the original, so the hunk id
-                                        // is preserved up to active_hunk.
-                                        let ((file_id, hunk_id), _) =
-                                            ui_state.get_hunk(active_hunk).unwrap();
-
-                                        ui_state.files[file_id].1.remove(hunk_id);
-                                        ui_state.hunk_commits.truncate(ui_state.hunk_count());
-                                        let mode = UiMode::Viewing {
-                                            active_hunk: active_hunk
-                                                .min(ui_state.hunk_count().saturating_sub(1)),
-                                        };
-                                        ui_state.set_mode(mode);
-                                    }
-                                }
-                                termion::event::Key::Char('p') => {
-                                    let pager = std::env::var("PAGER").ok();
-                                    let _ = spawn_with_input(
-                                        &mut sh(pager
-                                            .as_ref()
-                                            .map(|s| s.as_str())
-                                            .unwrap_or_else(|| "less")),
-                                        |stdin| {
-                                            let (_, (header, hunk_body)) =
-                                                ui_state.get_hunk(active_hunk).unwrap();
-                                            writeln!(stdin, "{header}")?;
-                                            write!(
-                                                stdin,
-                                                "{}",
-                                                render_hunk(hunk_body, usize::MAX,)
-                                            )?;
-                                            Ok(())
-                                        },
-                                    )
-                                    .and_then(|mut child| {
-                                        child.wait()?;
-                                        ui_state.force_redraw_gen = gen;
-                                        Ok(())
-                                    });
-                                }
-                                // TODO for editor?
-                                // TODO allow splitting a hunk?
-                                // termion::event::Key::Ctrl('e') => {}
-                                termion::event::Key::Ctrl('s')
-                                    if ui_state.should_save_commits() =>
-                                {
-                                    break 'ui_loop;
-                                }
-                                termion::event::Key::Char('q') => {
-                                    ui_state.dont_save = true;
-                                    break 'ui_loop;
-                                }
-                                termion::event::Key::Char(c @ '0'..='9') => {
-                                    if !ui_state.messages.contains_key(&c) {
-                                        let mode = UiMode::Editing {
-                                            commit: c,
-                                            message: Default::default(),
-                                            assign_to_hunk: Some(active_hunk),
-                                        };
-                                        ui_state.push_mode(mode);
-                                    } else {
-                                        ui_state.set_hunk_commit(active_hunk, c);
-                                    }
-                                }
-                                termion::event::Key::Backspace => {
-                                    ui_state.push_mode(UiMode::WaitingToEdit);
-                                }
-                                termion::event::Key::Left => {
-                                    let mode = UiMode::Viewing {
-                                        active_hunk: active_hunk.saturating_sub(1),
-                                    };
-                                    ui_state.set_mode(mode);
-                                }
-                                termion::event::Key::Right => {
-                                    if (active_hunk + 1) < ui_state.hunk_count() {
-                                        let mode = UiMode::Viewing {
-                                            active_hunk: active_hunk + 1,
-                                        };
-                                        ui_state.set_mode(mode);
-                                    }
-                                }
-                                _ => (),
-                            }
-                        }
-                    }
-                } // 'ui_loop
-                ui_state
             };
-            // TODO repeatedly edit a hunk and produce diffs
-            // until the user edits an empty file.
-            // That way can split a hunk even further
-            if ui_state.should_save_commits() {
-                let file_lookup: Vec<usize> = ui_state
-                    .hunks()
-                    .map(|((file_id, _), _)| file_id)
-                    .collect_vec();
-                let hunks = ui_state
-                    .hunks()
-                    .map(|(_, (_, b))| b.to_string())
-                    .collect_vec();
-                let hunks_for_commit: BTreeMap<CommitId, Vec<usize>> = ui_state
-                    .hunk_commits
-                    .iter()
-                    .copied()
-                    .enumerate()
-                    .flat_map(|(hunk_id, commit_id)| Some((commit_id?, hunk_id)))
-                    .into_group_map()
-                    .into_iter()
-                    .collect();
-                for (commit_id, hunk_ids) in hunks_for_commit.into_iter() {
-                    let CommitInfo { commit_message } = &ui_state.messages[&commit_id];
-                    log::debug!("Writing commit {commit_message}");
-                    for (file_id, hunk_ids) in &hunk_ids
-                        .into_iter()
-                        .group_by(|hunk_id| file_lookup[*hunk_id])
-                    {
-                        let output = get_output_with_input(
-                            &mut {
-                                let mut cmd = git();
-                                cmd.args(&["apply", "--reject"]);
-                                if diff_context_size == 0 {
-                                    cmd.arg("--unidiff-zero");
-                                }
-                                cmd
-                            },
-                            // git().args(&["apply", "--reject", "--recount"]),
-                            |stdin| {
-                                let (header, _) = &ui_state.files[file_id];
-                                writeln!(stdin, "{header}")?;
-                                log::debug!("HEADER: {header:?}");
-                                // TODO sort by line numbers?
-                                for hunk_id in hunk_ids {
-                                    let hunk_body = &hunks[hunk_id];
-                                    log::debug!("BODY: {header:?}");
-                                    writeln!(stdin, "{hunk_body}")?;
-                                }
-                                stdin.flush()?;
-                                Ok(())
-                            },
-                        )?;
-                        for line in output.split('\n') {
-                            if let Some(file) = line.strip_prefix("patching file ") {
-                                let file = file.trim_end();
-                                dbg!(file);
-                                get_output(git().args(&["add", file]))?;
-                            }
-                        }
-                        get_output(git().args(&["add", "-u"]))?;
-                    }
-                    get_output(git().args(&["commit", "-m", commit_message]))?;
-                }
-                // Sanity check
-                if ui_state.all_hunks_assigned() {
-                    if let Err(err) = get_output(git().args(&["diff", "--check", &commit])) {
-                        log::error!(
-                            "Failed sanity check diff, even though all hunks were assigned.\n\
-                            Error: {err:?}"
-                        );
-                        return Err(err);
-                    }
-                }
-                // get_output(
-                //     git().args(&["rebase", "--continue"]),
-                // )?;
-            } else {
-                get_output(git().args(&["rebase", "--abort"]))?;
-                // anyhow::bail!("Not all hunks assigned");
-            }
+            rebase_commands.insert(position, format!("x {exec}"));
+            std::fs::write(&todo, rebase_commands.iter().format("\n").to_string())
+                .with_context(|| format!("Failed to write to {todo:?}", todo = todo.display()))?;
+            // assert!(commit.starts_with(rebase_commands[0].split(' ').nth(1).unwrap()));
+        }
+        Opts::HunkSplit {
+            commit,
+            common,
+            message,
+            reverted,
+            in_place,
+        } => {
+            hunk_split(&commit, common, message, reverted, in_place)?;
         }
     }
     Ok(())
@@ -0,0 +1,294 @@
+use crate::backend::Backend;
+use crate::diff::{file_name, hunk_stat};
+use crate::event::Progress;
+use crate::ui::{CommitId, CommitInfo, UiState};
+use crate::{get_output, git, rev_parse};
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use std::fmt::Write as _;
+
+/// A commit the save step is going to create.
+pub struct PlannedCommit {
+    pub id: CommitId,
+    pub message: String,
+    /// One patch per touched file: the file header followed by the hunks
+    /// assigned to this commit, exactly as fed to `git apply`.
+    pub patches: Vec<String>,
+}
+
+impl PlannedCommit {
+    /// `(file, added, removed)` for every file in the commit.
+    pub fn diffstat(&self) -> Vec<(&str, usize, usize)> {
+        self.patches
+            .iter()
+            .map(|patch| {
+                let header_end = patch.find("\n@@").map_or(patch.len(), |i| i + 1);
+                let (header, hunks) = patch.split_at(header_end);
+                let (added, removed) = hunks
+                    .split("\n@@")
+                    .map(hunk_stat)
+                    .fold((0, 0), |(a, r), (added, removed)| (a + added, r + removed));
+                (file_name(header), added, removed)
+            })
+            .collect()
+    }
+}
+
+/// Changes made to every message of a split, on top of what was typed.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFormat {
+    /// Replaces each subject when there's more than one commit. `{subject}`
+    /// is the typed subject, `{i}` the commit's number and `{n}` the number
+    /// of commits, e.g. `{subject} (part {i}/{n})`.
+    pub subject_template: Option<String>,
+    /// Adds a `Split-from: <sha>` trailer.
+    pub split_from: Option<String>,
+}
+
+impl MessageFormat {
+    /// `message` as the `i`th (from 1) of `n` commits.
+    pub fn apply(&self, message: &str, i: usize, n: usize) -> String {
+        let mut message = match &self.subject_template {
+            Some(template) if n > 1 => {
+                let (subject, body) = message.split_once('\n').unwrap_or((message, ""));
+                let subject = template
+                    .replace("{subject}", subject)
+                    .replace("{i}", &i.to_string())
+                    .replace("{n}", &n.to_string());
+                format!("{subject}\n{body}")
+            }
+            _ => message.to_string(),
+        };
+        if let Some(sha) = &self.split_from {
+            message = add_trailers(&message, &[format!("Split-from: {sha}")]);
+        }
+        message
+    }
+}
+
+pub fn plan(ui_state: &UiState) -> Vec<PlannedCommit> {
+    let file_lookup: Vec<usize> = ui_state
+        .hunks()
+        .map(|((file_id, _), _)| file_id)
+        .collect_vec();
+    let hunks = ui_state.hunks().map(|(_, (_, b))| b).collect_vec();
+    let by_commit = ui_state.hunks_by_commit();
+    let n = by_commit.len();
+    by_commit
+        .into_iter()
+        .enumerate()
+        .map(|(i, (id, hunk_ids))| {
+            let CommitInfo { commit_message } = &ui_state.messages[&id];
+            let patches = hunk_ids
+                .into_iter()
+                .group_by(|hunk_id| file_lookup[*hunk_id])
+                .into_iter()
+                .map(|(file_id, hunk_ids)| {
+                    let (header, _) = &ui_state.files[file_id];
+                    let mut patch = format!("{header}\n");
+                    // TODO sort by line numbers?
+                    for hunk_id in hunk_ids {
+                        writeln!(patch, "{}", hunks[hunk_id]).unwrap();
+                    }
+                    patch
+                })
+                .collect();
+            PlannedCommit {
+                id,
+                message: ui_state.message_format.apply(commit_message, i + 1, n),
+                patches,
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+    /// In git's raw format, `<seconds> <+hhmm>`.
+    pub date: String,
+}
+
+/// What the new commits keep from the commit they were split from.
+#[derive(Clone, Debug, Default)]
+pub struct Original {
+    pub author: Option<Author>,
+    /// Lines like `Signed-off-by: A U Thor <author@example.com>`.
+    pub trailers: Vec<String>,
+}
+
+fn is_trailer(line: &str) -> bool {
+    line.split_once(": ").is_some_and(|(key, _)| {
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// The trailer block at the end of `message`, if it has a body.
+fn trailers(message: &str) -> Vec<&str> {
+    let paragraphs = message.trim_end().split("\n\n").collect_vec();
+    match paragraphs.as_slice() {
+        [_, .., last] if last.lines().all(is_trailer) => last.lines().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `message` with the `new` trailers it doesn't have yet, joining its
+/// trailer block if it ends in one.
+fn add_trailers(message: &str, new: &[String]) -> String {
+    let message = message.trim_end();
+    let missing = new
+        .iter()
+        .filter(|trailer| !message.lines().any(|line| line == trailer.as_str()))
+        .join("\n");
+    if missing.is_empty() {
+        message.to_string()
+    } else if trailers(message).is_empty() {
+        format!("{message}\n\n{missing}")
+    } else {
+        format!("{message}\n{missing}")
+    }
+}
+
+impl Original {
+    /// Reads the author and trailers of `commit`, leaving the author out if
+    /// `reset_author` is set.
+    pub fn read(commit: &str, reset_author: bool) -> Result<Self> {
+        let log = get_output(git().args([
+            "log",
+            "-1",
+            "--format=%an%x00%ae%x00%ad%x00%B",
+            "--date=raw",
+            commit,
+        ]))?;
+        let mut fields = log.splitn(4, '\0');
+        let mut field = || fields.next().unwrap_or_default().to_string();
+        let author = Author {
+            name: field(),
+            email: field(),
+            date: field(),
+        };
+        Ok(Self {
+            author: (!reset_author).then_some(author),
+            trailers: trailers(&field()).into_iter().map(str::to_string).collect(),
+        })
+    }
+
+    /// `message` with the original trailers it doesn't have yet.
+    pub fn message(&self, message: &str) -> String {
+        add_trailers(message, &self.trailers)
+    }
+}
+
+/// Writes the planned commits in order with `write`, reporting progress.
+fn write_commits(
+    plan: &[PlannedCommit],
+    original: &Original,
+    progress: &Progress,
+    mut write: impl FnMut(&PlannedCommit, &str) -> Result<()>,
+) -> Result<()> {
+    for (i, commit) in plan.iter().enumerate() {
+        let subject = commit.message.split('\n').next().unwrap();
+        progress.report(format!(
+            "[{i}/{n}] {id}: {subject}",
+            i = i + 1,
+            n = plan.len(),
+            id = commit.id
+        ));
+        let message = original.message(&commit.message);
+        log::debug!("Writing commit {message}");
+        write(commit, &message)
+            .with_context(|| format!("Failed to create commit {} ({subject})", commit.id))?;
+    }
+    Ok(())
+}
+
+/// Creates the planned commits on HEAD. If one can't be created, HEAD goes
+/// back to where it was, so the save can be tried again.
+pub fn save(
+    plan: &[PlannedCommit],
+    backend: &dyn Backend,
+    original: &Original,
+    progress: &Progress,
+) -> Result<()> {
+    log::debug!("Saving with {}", backend.name());
+    let head = rev_parse("HEAD")?;
+    let written = write_commits(plan, original, progress, |commit, message| {
+        backend.commit(&commit.patches, message, original.author.as_ref())
+    });
+    if let Err(err) = written {
+        backend.reset(&head)?;
+        return Err(err.context("No commits were created"));
+    }
+    backend.finish()
+}
+
+/// Like `save`, but chains the commits onto `parent` without touching HEAD,
+/// the index or the working tree. Returns the last commit.
+pub fn save_onto(
+    plan: &[PlannedCommit],
+    backend: &dyn Backend,
+    original: &Original,
+    parent: &str,
+    progress: &Progress,
+) -> Result<String> {
+    log::debug!("Saving onto {parent} with {}", backend.name());
+    let mut tip = parent.to_string();
+    write_commits(plan, original, progress, |commit, message| {
+        tip = backend.commit_tree(&tip, &commit.patches, message, original.author.as_ref())?;
+        Ok(())
+    })?;
+    Ok(tip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_trailers_starts_or_joins_a_block() {
+        let trailer = ["Split-from: abc".to_string()];
+        assert_eq!(add_trailers("Fix\n", &trailer), "Fix\n\nSplit-from: abc");
+        assert_eq!(
+            add_trailers("Fix\n\nBody\n", &trailer),
+            "Fix\n\nBody\n\nSplit-from: abc"
+        );
+        assert_eq!(
+            add_trailers("Fix\n\nSigned-off-by: A <a@b>", &trailer),
+            "Fix\n\nSigned-off-by: A <a@b>\nSplit-from: abc"
+        );
+        // A subject that looks like a trailer isn't a trailer block.
+        assert_eq!(
+            add_trailers("fix: typo", &trailer),
+            "fix: typo\n\nSplit-from: abc"
+        );
+    }
+
+    #[test]
+    fn add_trailers_skips_existing_ones() {
+        let message = "Fix\n\nSplit-from: abc";
+        assert_eq!(
+            add_trailers(message, &["Split-from: abc".to_string()]),
+            message
+        );
+    }
+
+    #[test]
+    fn apply_template_to_several_commits() {
+        let format = MessageFormat {
+            subject_template: Some("{subject} (part {i}/{n})".to_string()),
+            split_from: None,
+        };
+        assert_eq!(format.apply("Fix\n\nBody", 2, 3), "Fix (part 2/3)\n\nBody");
+        assert_eq!(format.apply("Fix\n\nBody", 1, 1), "Fix\n\nBody");
+    }
+
+    #[test]
+    fn apply_split_from() {
+        let format = MessageFormat {
+            subject_template: Some("{i}: {subject}".to_string()),
+            split_from: Some("abc".to_string()),
+        };
+        assert_eq!(format.apply("Fix", 1, 2), "1: Fix\n\nSplit-from: abc");
+    }
+}
@@ -0,0 +1,200 @@
+//! Undoing and picking up splits: where HEAD was before the last one, and
+//! the state of a split that stopped partway through its rebase or, split
+//! in place, without one.
+
+use crate::{get_output, git, git_path, rev_parse, sh};
+use anyhow::{Context, Result};
+
+/// Where HEAD was before the last split.
+const ORIG_HEAD: &str = "refs/kgit-split/orig-head";
+
+/// The unfinished `--in-place` split, if any, under `.git/`.
+const IN_PLACE: &str = "kgit-split-in-place";
+
+/// A split of HEAD in place that hasn't finished.
+struct InPlace {
+    /// The commit being split, which HEAD was on.
+    commit: String,
+    /// Whether the user's own rebase was stopped at `commit`.
+    in_rebase: bool,
+    /// The `hunk-split` command that splits it.
+    exec: String,
+}
+
+/// Remembers HEAD for `restore`, before a split moves it.
+pub fn save_orig_head() -> Result<()> {
+    get_output(git().args([
+        "update-ref",
+        "-m",
+        "kgit-split: before split",
+        ORIG_HEAD,
+        "HEAD",
+    ]))?;
+    Ok(())
+}
+
+fn rebase_in_progress() -> Result<bool> {
+    Ok(git_path("rebase-merge")?.exists() || git_path("rebase-apply")?.exists())
+}
+
+/// Whether the rebase in progress is one a split started, rather than the
+/// user's own: it runs `hunk-split`.
+fn split_rebase_in_progress() -> Result<bool> {
+    for todo in ["rebase-merge/done", "rebase-merge/git-rebase-todo"] {
+        let Ok(todo) = std::fs::read_to_string(git_path(todo)?) else {
+            continue;
+        };
+        if todo.contains(" hunk-split --commit ") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Notes that `commit`, at HEAD, is being split in place by `exec`, for
+/// `restore` and `resume` in case the split doesn't finish.
+pub fn start_in_place(commit: &str, exec: &str) -> Result<()> {
+    let in_rebase = if rebase_in_progress()? { "rebase" } else { "" };
+    std::fs::write(
+        git_path(IN_PLACE)?,
+        format!("{commit}\n{in_rebase}\n{exec}\n"),
+    )?;
+    Ok(())
+}
+
+/// Forgets the in-place split once it is done, or was given up.
+pub fn finish_in_place() -> Result<()> {
+    let path = git_path(IN_PLACE)?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn in_place() -> Result<Option<InPlace>> {
+    let Ok(record) = std::fs::read_to_string(git_path(IN_PLACE)?) else {
+        return Ok(None);
+    };
+    let mut lines = record.lines();
+    match (lines.next(), lines.next(), lines.next()) {
+        (Some(commit), Some(in_rebase), Some(exec)) => Ok(Some(InPlace {
+            commit: commit.to_string(),
+            in_rebase: in_rebase == "rebase",
+            exec: exec.to_string(),
+        })),
+        _ => anyhow::bail!("Can't read {IN_PLACE}"),
+    }
+}
+
+/// What the user is in the middle of, if anything: "rebase", "merge",
+/// "cherry-pick" or "revert".
+pub fn operation_in_progress() -> Result<Option<&'static str>> {
+    if rebase_in_progress()? {
+        return Ok(Some("rebase"));
+    }
+    for (file, operation) in [
+        ("MERGE_HEAD", "merge"),
+        ("CHERRY_PICK_HEAD", "cherry-pick"),
+        ("REVERT_HEAD", "revert"),
+    ] {
+        if git_path(file)?.exists() {
+            return Ok(Some(operation));
+        }
+    }
+    Ok(None)
+}
+
+/// The command that split the commit being split, if the rebase stopped
+/// right after running it, i.e. the split failed, and that commit.
+fn stopped_split() -> Result<Option<(String, String)>> {
+    let Ok(done) = std::fs::read_to_string(git_path("rebase-merge/done")?) else {
+        return Ok(None);
+    };
+    let Some(exec) = done
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| line.strip_prefix("exec ").or(line.strip_prefix("x ")))
+        .filter(|exec| exec.contains(" hunk-split "))
+    else {
+        return Ok(None);
+    };
+    let commit = exec
+        .split(" --commit ")
+        .nth(1)
+        .and_then(|rest| rest.split(' ').next())
+        .context("No commit in the split command")?;
+    Ok(Some((exec.to_string(), commit.to_string())))
+}
+
+/// Aborts a running split, or resets HEAD to where it was before the last
+/// one. The working tree is left alone, so what the split committed shows up
+/// as staged changes if it differs. An unfinished split in place goes back
+/// to the commit it split, working tree included, and leaves the user's
+/// rebase alone.
+pub fn restore() -> Result<()> {
+    if let Some(in_place) = in_place()? {
+        get_output(git().args(["reset", "-q", "--hard", &in_place.commit]))?;
+        get_output(git().args(["update-ref", "-d", ORIG_HEAD]))?;
+        finish_in_place()?;
+        eprintln!("HEAD is back at {}", in_place.commit);
+        if in_place.in_rebase {
+            eprintln!("Your rebase is still stopped there");
+        }
+        return Ok(());
+    }
+    if split_rebase_in_progress()? {
+        get_output(git().args(["rebase", "--abort"]))?;
+    }
+    let orig_head = rev_parse(ORIG_HEAD).context("There is no split to restore")?;
+    get_output(git().args(["reset", "--soft", &orig_head]))?;
+    get_output(git().args(["update-ref", "-d", ORIG_HEAD]))?;
+    eprintln!("HEAD is back at {orig_head}");
+    Ok(())
+}
+
+/// Runs the split of `commit` by `exec` again, from its revert commit if it
+/// got that far, dropping anything committed after it.
+fn split_again(commit: &str, exec: &str) -> Result<()> {
+    let revert_subject = format!("@split Revert {commit}");
+    let revert = get_output(git().args(["log", "--format=%H %s", &format!("{commit}..HEAD")]))?
+        .lines()
+        .find_map(|line| {
+            let (sha, subject) = line.split_once(' ')?;
+            subject
+                .starts_with(&revert_subject)
+                .then(|| sha.to_string())
+        });
+    get_output(git().args(["reset", "-q", "--hard", revert.as_deref().unwrap_or("HEAD")]))?;
+    // The same options as the first time.
+    let exec = match revert {
+        Some(_) => format!("{exec} --reverted"),
+        None => exec.to_string(),
+    };
+    anyhow::ensure!(sh(&exec).status()?.success(), "The split failed again");
+    Ok(())
+}
+
+/// Reopens the hunks of a split whose commits couldn't be created, dropping
+/// the ones that were, then continues the rebase. If the rebase stopped
+/// later on, e.g. on a conflict in a commit after the split one, this only
+/// continues it. A split in place is only reopened: the rebase, if any, is
+/// the user's.
+pub fn resume() -> Result<()> {
+    if let Some(in_place) = in_place()? {
+        return split_again(&in_place.commit, &in_place.exec);
+    }
+    anyhow::ensure!(rebase_in_progress()?, "There is no split to resume");
+    if let Some((exec, commit)) = stopped_split()? {
+        split_again(&commit, &exec)?;
+        if !rebase_in_progress()? {
+            // The split was aborted.
+            return Ok(());
+        }
+    }
+    anyhow::ensure!(
+        git().args(["rebase", "--continue"]).status()?.success(),
+        "The rebase stopped again; fix it up and run `git split resume`"
+    );
+    Ok(())
+}
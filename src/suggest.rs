@@ -0,0 +1,191 @@
+use crate::diff::file_name;
+use crate::ui::{CommitId, UiState};
+use crate::{get_output, git};
+use itertools::Itertools;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// How hunks are clustered into suggested commits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    Directory,
+    CargoMember,
+    FileType,
+    Identifiers,
+}
+
+impl Strategy {
+    pub const ALL: [Self; 4] = [
+        Self::Directory,
+        Self::CargoMember,
+        Self::FileType,
+        Self::Identifiers,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Directory => "top-level directory",
+            Self::CargoMember => "cargo workspace member",
+            Self::FileType => "file type",
+            Self::Identifiers => "shared identifiers",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|s| *s == self).unwrap();
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+}
+
+/// A proposed commit: `hunks` would go to `commit`, with `label` as the
+/// draft message.
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub label: String,
+    pub commit: Option<CommitId>,
+    pub hunks: Vec<usize>,
+}
+
+/// The directories with a `Cargo.toml`, relative to the top of the
+/// repository, in `commit` or else in the working tree.
+fn cargo_manifest_dirs(commit: Option<&str>) -> BTreeSet<String> {
+    let files = match commit {
+        Some(commit) => {
+            get_output(git().args(["ls-tree", "-r", "--full-tree", "--name-only", commit]))
+        }
+        None => get_output(git().args([
+            "ls-files",
+            "--full-name",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+            "--",
+            ":/",
+        ])),
+    };
+    files
+        .unwrap_or_default()
+        .lines()
+        .map(Path::new)
+        .filter(|file| file.file_name().is_some_and(|name| name == "Cargo.toml"))
+        .filter_map(|file| Some(file.parent()?.display().to_string()))
+        .collect()
+}
+
+/// The directory of the closest `Cargo.toml` above `path`, out of
+/// `manifest_dirs`.
+fn cargo_member(manifest_dirs: &BTreeSet<String>, path: &str) -> String {
+    Path::new(path)
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.display().to_string())
+        .find(|dir| manifest_dirs.contains(dir))
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "(root)".to_string())
+}
+
+/// Identifiers on the changed lines of a hunk.
+fn identifiers<'a>(pattern: &Regex, hunk: &'a str) -> BTreeSet<&'a str> {
+    hunk.split('\n')
+        .skip(1)
+        .filter(|line| line.starts_with(['+', '-']))
+        .flat_map(|line| pattern.find_iter(&line[1..]).map(|m| m.as_str()))
+        .collect()
+}
+
+/// Clusters hunks sharing an identifier that isn't changed nearly
+/// everywhere, returning the most common shared identifier of each cluster.
+fn identifier_clusters(hunks: &[&str]) -> Vec<String> {
+    let pattern = Regex::new(r"[A-Za-z_][A-Za-z0-9_]{3,}").unwrap();
+    let per_hunk = hunks
+        .iter()
+        .map(|hunk| identifiers(&pattern, hunk))
+        .collect_vec();
+    let mut users: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (idx, idents) in per_hunk.iter().enumerate() {
+        for ident in idents {
+            users.entry(ident).or_default().push(idx);
+        }
+    }
+    // Union-find over hunks.
+    let mut parent = (0..hunks.len()).collect_vec();
+    fn root(parent: &mut [usize], mut idx: usize) -> usize {
+        while parent[idx] != idx {
+            parent[idx] = parent[parent[idx]];
+            idx = parent[idx];
+        }
+        idx
+    }
+    let common = (hunks.len() / 2).max(2);
+    for idxs in users.values() {
+        if idxs.len() < 2 || idxs.len() > common {
+            continue;
+        }
+        for idx in &idxs[1..] {
+            let (a, b) = (root(&mut parent, idxs[0]), root(&mut parent, *idx));
+            parent[a] = b;
+        }
+    }
+    let roots = (0..hunks.len())
+        .map(|idx| root(&mut parent, idx))
+        .collect_vec();
+    (0..hunks.len())
+        .map(|idx| {
+            users
+                .iter()
+                .filter(|(_, idxs)| idxs.len() <= common)
+                .filter(|(_, idxs)| idxs.iter().any(|i| roots[*i] == roots[idx]))
+                .max_by_key(|(_, idxs)| idxs.len())
+                .filter(|(_, idxs)| idxs.len() > 1)
+                .map_or_else(|| "(unrelated)".to_string(), |(ident, _)| ident.to_string())
+        })
+        .collect()
+}
+
+/// Proposes groups of hunks for `strategy`, biggest first, each with the
+/// next commit id that isn't in use yet.
+pub fn suggest(ui_state: &UiState, strategy: Strategy) -> Vec<Group> {
+    let hunks = ui_state.hunks().collect_vec();
+    let manifest_dirs = match strategy {
+        Strategy::CargoMember => cargo_manifest_dirs(ui_state.source_commit.as_deref()),
+        _ => BTreeSet::new(),
+    };
+    let keys = match strategy {
+        Strategy::Identifiers => {
+            identifier_clusters(&hunks.iter().map(|(_, (_, hunk))| *hunk).collect_vec())
+        }
+        _ => hunks
+            .iter()
+            .map(|(_, (header, _))| {
+                let path = file_name(header);
+                match strategy {
+                    Strategy::Directory => match path.split_once('/') {
+                        Some((dir, _)) => format!("{dir}/"),
+                        None => "(root)".to_string(),
+                    },
+                    Strategy::CargoMember => cargo_member(&manifest_dirs, path),
+                    Strategy::FileType => match Path::new(path).extension() {
+                        Some(ext) => format!("*.{}", ext.to_string_lossy()),
+                        None => "(no extension)".to_string(),
+                    },
+                    Strategy::Identifiers => unreachable!(),
+                }
+            })
+            .collect_vec(),
+    };
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (idx, key) in keys.into_iter().enumerate() {
+        groups.entry(key).or_default().push(idx);
+    }
+    let mut free_ids = ('1'..='9').filter(|id| !ui_state.messages.contains_key(id));
+    groups
+        .into_iter()
+        .sorted_by_key(|(_, hunks)| std::cmp::Reverse(hunks.len()))
+        .map(|(label, hunks)| Group {
+            label,
+            commit: free_ids.next(),
+            hunks,
+        })
+        .collect()
+}
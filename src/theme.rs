@@ -0,0 +1,486 @@
+use crate::{get_output, git};
+use once_cell::sync::Lazy;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Color {
+    /// One of the 16 basic colors, 8-15 being the bright variants.
+    Ansi(u8),
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// xterm's values for the 16 basic colors, used when downsampling.
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+impl Color {
+    fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Self::Ansi(n) => ANSI_RGB[n as usize % 16],
+            Self::Indexed(n) if n < 16 => ANSI_RGB[n as usize],
+            Self::Indexed(n) if n < 232 => {
+                let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                let n = n - 16;
+                (level(n / 36), level(n / 6 % 6), level(n % 6))
+            }
+            Self::Indexed(n) => {
+                let v = 8 + (n - 232) * 10;
+                (v, v, v)
+            }
+            Self::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    /// Approximates the color with what the terminal supports.
+    fn downsample(self, depth: ColorDepth) -> Option<Self> {
+        match (self, depth) {
+            (_, ColorDepth::None) => None,
+            (Self::Ansi(_), _) | (_, ColorDepth::TrueColor) => Some(self),
+            (Self::Indexed(_), ColorDepth::Ansi256) => Some(self),
+            (Self::Rgb(r, g, b), ColorDepth::Ansi256) => {
+                let level = |v: u8| ((v as u16 * 5 + 127) / 255) as u8;
+                Some(Self::Indexed(16 + 36 * level(r) + 6 * level(g) + level(b)))
+            }
+            (Self::Indexed(_) | Self::Rgb(..), ColorDepth::Ansi16) => {
+                let (r, g, b) = self.rgb();
+                let distance = |&(r2, g2, b2): &(u8, u8, u8)| {
+                    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+                    d(r, r2) + d(g, g2) + d(b, b2)
+                };
+                let (n, _) = ANSI_RGB
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, rgb)| distance(rgb))
+                    .unwrap();
+                Some(Self::Ansi(n as u8))
+            }
+        }
+    }
+
+    fn write_sgr(self, f: &mut fmt::Formatter<'_>, background: bool) -> fmt::Result {
+        let base = if background { 40 } else { 30 };
+        match self {
+            Self::Ansi(n) if n < 8 => write!(f, ";{}", base + n as u16),
+            Self::Ansi(n) => write!(f, ";{}", base + 60 + (n as u16 - 8)),
+            Self::Indexed(n) => write!(f, ";{};5;{n}", base + 8),
+            Self::Rgb(r, g, b) => write!(f, ";{};2;{r};{g};{b}", base + 8),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub const PLAIN: Self = Self {
+        fg: None,
+        bg: None,
+        bold: false,
+        dim: false,
+        italic: false,
+        underline: false,
+        reverse: false,
+    };
+
+    pub const BOLD: Self = Self {
+        bold: true,
+        ..Self::PLAIN
+    };
+
+    pub const DIM: Self = Self {
+        dim: true,
+        ..Self::PLAIN
+    };
+
+    pub const REVERSE: Self = Self {
+        reverse: true,
+        ..Self::PLAIN
+    };
+
+    pub const fn fg(color: Color) -> Self {
+        Self {
+            fg: Some(color),
+            ..Self::PLAIN
+        }
+    }
+
+    /// Parses git's color syntax (`git help config`, "color"), e.g.
+    /// `"red bold"`, `"brightgreen"`, `"#ff0000 ul"` or `"208 reverse"`.
+    pub fn parse_git(value: &str) -> Option<Self> {
+        fn color(word: &str) -> Option<Option<Color>> {
+            const NAMES: [&str; 8] = [
+                "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+            ];
+            if word == "normal" || word == "default" {
+                return Some(None);
+            }
+            if let Some(hex) = word.strip_prefix('#') {
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                if hex.len() != 6 {
+                    return None;
+                }
+                return Some(Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?)));
+            }
+            if let Ok(n) = word.parse::<u8>() {
+                return Some(Some(Color::Indexed(n)));
+            }
+            let (bright, name) = match word.strip_prefix("bright") {
+                Some(name) => (8, name),
+                None => (0, word),
+            };
+            let n = NAMES.iter().position(|c| *c == name)? as u8;
+            Some(Some(Color::Ansi(n + bright)))
+        }
+        let mut style = Self::default();
+        let mut colors = 0;
+        for word in value.split_whitespace() {
+            match word {
+                "bold" => style.bold = true,
+                "dim" => style.dim = true,
+                "italic" => style.italic = true,
+                "ul" => style.underline = true,
+                "reverse" => style.reverse = true,
+                // `normal` is a color, not the negation of an attribute.
+                word if (word.starts_with("no") && word != "normal")
+                    || word == "blink"
+                    || word == "strike" => {}
+                word => {
+                    let color = color(word)?;
+                    match colors {
+                        0 => style.fg = color,
+                        1 => style.bg = color,
+                        _ => return None,
+                    }
+                    colors += 1;
+                }
+            }
+        }
+        Some(style)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    /// Attributes like bold and reverse only.
+    None,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    fn detect() -> Self {
+        if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            return Self::None;
+        }
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            Self::TrueColor
+        } else if term.contains("256") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+}
+
+pub struct Theme {
+    pub name: &'static str,
+    pub depth: ColorDepth,
+    /// Whether to write escape sequences at all.
+    pub ansi: bool,
+    pub added: Style,
+    pub removed: Style,
+    pub frag: Style,
+    pub warning: Style,
+    pub error: Style,
+    /// Lines or rulers that should fade into the background.
+    pub faint: Style,
+    /// Used in turn for each commit id, as they are first shown.
+    pub commits: Vec<Style>,
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        use Color::Ansi;
+        Self {
+            name: "dark",
+            depth: ColorDepth::Ansi16,
+            ansi: true,
+            added: Style::fg(Ansi(2)),
+            removed: Style::fg(Ansi(1)),
+            frag: Style::fg(Ansi(6)),
+            warning: Style::fg(Ansi(3)),
+            error: Style::fg(Ansi(1)),
+            faint: Style::DIM,
+            commits: [9, 11, 12, 10, 14, 13, 8, 15, 4, 1]
+                .into_iter()
+                .map(|n| Style::fg(Ansi(n)))
+                .collect(),
+        }
+    }
+
+    /// Darker colors that stay readable on a light background.
+    pub fn light() -> Self {
+        use Color::{Ansi, Indexed};
+        Self {
+            name: "light",
+            added: Style::fg(Indexed(28)),
+            removed: Style::fg(Indexed(124)),
+            frag: Style::fg(Indexed(25)),
+            warning: Style::fg(Indexed(130)),
+            error: Style::fg(Indexed(160)),
+            commits: [
+                Ansi(1),
+                Ansi(4),
+                Ansi(2),
+                Ansi(5),
+                Ansi(6),
+                Indexed(130),
+                Indexed(54),
+                Indexed(24),
+                Indexed(94),
+                Ansi(0),
+            ]
+            .into_iter()
+            .map(Style::fg)
+            .collect(),
+            ..Self::dark()
+        }
+    }
+
+    pub fn monochrome() -> Self {
+        Self {
+            name: "monochrome",
+            depth: ColorDepth::None,
+            added: Style::PLAIN,
+            removed: Style::PLAIN,
+            frag: Style::PLAIN,
+            warning: Style::BOLD,
+            error: Style::BOLD,
+            commits: Vec::new(),
+            ..Self::dark()
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "monochrome" | "mono" | "none" => Some(Self::monochrome()),
+            _ => None,
+        }
+    }
+
+    /// The escape sequence turning on `style`, or nothing.
+    pub fn paint(&self, style: Style) -> Painted {
+        Painted {
+            style,
+            depth: self.depth,
+            ansi: self.ansi,
+        }
+    }
+
+    pub fn reset(&self) -> &'static str {
+        if self.ansi {
+            "\x1b[0m"
+        } else {
+            ""
+        }
+    }
+}
+
+pub struct Painted {
+    style: Style,
+    depth: ColorDepth,
+    ansi: bool,
+}
+
+impl fmt::Display for Painted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Style {
+            fg,
+            bg,
+            bold,
+            dim,
+            italic,
+            underline,
+            reverse,
+        } = self.style;
+        let fg = fg.and_then(|c| c.downsample(self.depth));
+        let bg = bg.and_then(|c| c.downsample(self.depth));
+        if !self.ansi
+            || (fg, bg) == (None, None) && !(bold || dim || italic || underline || reverse)
+        {
+            return Ok(());
+        }
+        write!(f, "\x1b[0")?;
+        for (on, code) in [
+            (bold, 1),
+            (dim, 2),
+            (italic, 3),
+            (underline, 4),
+            (reverse, 7),
+        ] {
+            if on {
+                write!(f, ";{code}")?;
+            }
+        }
+        if let Some(fg) = fg {
+            fg.write_sgr(f, false)?;
+        }
+        if let Some(bg) = bg {
+            bg.write_sgr(f, true)?;
+        }
+        write!(f, "m")
+    }
+}
+
+fn git_config(key: &str) -> Option<String> {
+    let value = get_output(git().args(["config", "--get", key])).ok()?;
+    Some(value.trim().to_string())
+}
+
+/// Picks the theme from `KGIT_SPLIT_THEME` (`dark`, `light` or
+/// `monochrome`), `NO_COLOR`, `TERM` and git's `color.*` settings.
+fn load() -> Theme {
+    let name = std::env::var(crate::ENV_THEME).unwrap_or_default();
+    let mut theme = Theme::builtin(&name).unwrap_or_else(|| {
+        if !name.is_empty() {
+            log::warn!("Unknown theme {name:?}, using dark");
+        }
+        Theme::dark()
+    });
+    let colors_disabled = ["color.diff", "color.ui"]
+        .into_iter()
+        .filter_map(git_config)
+        .any(|v| v == "never" || v == "false");
+    theme.depth = if colors_disabled || theme.name == "monochrome" {
+        ColorDepth::None
+    } else {
+        ColorDepth::detect()
+    };
+    theme.ansi = std::env::var("TERM").map_or(true, |term| term != "dumb");
+    for (key, style) in [
+        ("color.diff.old", &mut theme.removed),
+        ("color.diff.new", &mut theme.added),
+        ("color.diff.frag", &mut theme.frag),
+    ] {
+        if let Some(value) = git_config(key) {
+            match Style::parse_git(&value) {
+                Some(parsed) => *style = parsed,
+                None => log::warn!("Can't parse {key} = {value:?}"),
+            }
+        }
+    }
+    theme
+}
+
+static THEME: Lazy<Theme> = Lazy::new(load);
+
+pub fn theme() -> &'static Theme {
+    &THEME
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_git_colors_and_attributes() {
+        assert_eq!(
+            Style::parse_git("red bold"),
+            Some(Style {
+                fg: Some(Color::Ansi(1)),
+                bold: true,
+                ..Style::PLAIN
+            })
+        );
+        assert_eq!(
+            Style::parse_git("brightgreen #0000ff ul"),
+            Some(Style {
+                fg: Some(Color::Ansi(10)),
+                bg: Some(Color::Rgb(0, 0, 255)),
+                underline: true,
+                ..Style::PLAIN
+            })
+        );
+        assert_eq!(
+            Style::parse_git("208 reverse nobold"),
+            Some(Style {
+                fg: Some(Color::Indexed(208)),
+                reverse: true,
+                ..Style::PLAIN
+            })
+        );
+    }
+
+    #[test]
+    fn parse_git_normal_is_a_color() {
+        assert_eq!(
+            Style::parse_git("normal red"),
+            Some(Style {
+                bg: Some(Color::Ansi(1)),
+                ..Style::PLAIN
+            })
+        );
+        assert_eq!(Style::parse_git("default default"), Some(Style::PLAIN));
+    }
+
+    #[test]
+    fn parse_git_rejects_garbage() {
+        assert_eq!(Style::parse_git("reddish"), None);
+        assert_eq!(Style::parse_git("#12345"), None);
+        assert_eq!(Style::parse_git("red blue green"), None);
+    }
+
+    #[test]
+    fn downsample() {
+        let red = Color::Rgb(255, 0, 0);
+        assert_eq!(red.downsample(ColorDepth::TrueColor), Some(red));
+        assert_eq!(
+            red.downsample(ColorDepth::Ansi256),
+            Some(Color::Indexed(196))
+        );
+        assert_eq!(red.downsample(ColorDepth::Ansi16), Some(Color::Ansi(9)));
+        assert_eq!(
+            Color::Indexed(196).downsample(ColorDepth::Ansi16),
+            Some(Color::Ansi(9))
+        );
+        assert_eq!(
+            Color::Indexed(232).downsample(ColorDepth::Ansi16),
+            Some(Color::Ansi(0))
+        );
+        assert_eq!(
+            Color::Ansi(3).downsample(ColorDepth::Ansi16),
+            Some(Color::Ansi(3))
+        );
+        assert_eq!(red.downsample(ColorDepth::None), None);
+    }
+}
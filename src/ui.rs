@@ -0,0 +1,1286 @@
+use crate::backend::CommitOptions;
+use crate::deps::{self, Dependency, Move};
+use crate::diff::{is_whitespace_only, FileDiff};
+use crate::editor::{TextEditor, SUBJECT_WIDTH};
+use crate::event::{Event, Events, Progress};
+use crate::fmt::*;
+use crate::frame::Screen;
+use crate::keymap::{self, Action};
+use crate::save::{self, PlannedCommit};
+use crate::suggest::{self, Group, Strategy};
+use crate::theme::{theme, Style};
+use crate::{edit, render_hunk, render_hunk_side_by_side, sh, spawn_with_input};
+use anyhow::Result;
+use itertools::Itertools;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{stdout, Write as _};
+use std::sync::Arc;
+
+pub type CommitId = char;
+
+/// Below this width side-by-side hunks fall back to unified ones.
+pub const SIDE_BY_SIDE_MIN_WIDTH: u16 = 120;
+
+/// `((file_id, hunk_id), (header, hunk))`
+pub type HunkRef<'a> = ((usize, usize), (&'a str, &'a str));
+
+#[derive(Debug)]
+pub enum UiMode {
+    Editing {
+        commit: CommitId,
+        message: TextEditor,
+        /// Hunks to assign to `commit` once the message is saved.
+        assign_to_hunks: Vec<usize>,
+    },
+    Viewing {
+        active_hunk: usize,
+    },
+    WaitingToEdit,
+    /// Waiting for the commit id to assign `hunks` to. `what` describes the
+    /// hunks in the prompt.
+    WaitingToAssign {
+        hunks: Vec<usize>,
+        what: String,
+    },
+    /// Key help for the mode below it on the mode stack.
+    Help,
+    /// Creating the commits in the background.
+    Saving {
+        log: Vec<String>,
+        error: Option<String>,
+    },
+    /// Selecting (and reordering) entries of `UiState::commit_order`.
+    CommitList {
+        active_commit: usize,
+    },
+    /// Proposed groups of hunks from `suggest::suggest`.
+    Suggesting {
+        strategy: Strategy,
+        groups: Vec<Group>,
+        active_group: usize,
+    },
+    /// The patch that saving would apply for `commit`, scrolled down by
+    /// `scroll` lines.
+    Preview {
+        commit: CommitId,
+        scroll: usize,
+    },
+}
+
+impl UiMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Editing { .. } => "editing message",
+            Self::Viewing { .. } => "hunks",
+            Self::WaitingToEdit => "pick commit to edit",
+            Self::WaitingToAssign { .. } => "pick commit to assign to",
+            Self::Help => "help",
+            Self::Saving { .. } => "saving",
+            Self::CommitList { .. } => "commit list",
+            Self::Preview { .. } => "preview",
+            Self::Suggesting { .. } => "suggestions",
+        }
+    }
+}
+
+impl Default for UiMode {
+    fn default() -> Self {
+        Self::Viewing { active_hunk: 0 }
+    }
+}
+
+pub struct CommitInfo {
+    pub commit_message: String,
+}
+
+#[derive(Default)]
+pub struct UiState {
+    pub dont_save: bool,
+    pub files: Vec<FileDiff>,
+    /// Set whenever something on screen may have changed; cleared after
+    /// drawing.
+    pub dirty: bool,
+    /// Rewrite every row on the next draw instead of only the changed ones.
+    pub force_redraw: bool,
+    pub terminal_size: (u16, u16),
+    /// Counts `Event::Tick`s while a background task runs, for the spinner.
+    pub ticks: usize,
+    /// Set once the commits were created.
+    pub saved: bool,
+    /// Why the last save failed, if it did.
+    pub save_error: Option<String>,
+    /// Passed to `save`; `--no-verify` can be toggled.
+    pub commit_options: CommitOptions,
+    pub allow_partial: bool,
+    /// Show hunks as old and new columns, when the terminal is at least
+    /// `SIDE_BY_SIDE_MIN_WIDTH` wide.
+    pub side_by_side: bool,
+    /// Applied to the messages when saving, and shown in the commit list.
+    pub message_format: save::MessageFormat,
+    /// The commit the hunks come from, or `None` for the working tree.
+    pub source_commit: Option<String>,
+    /// The `deps::Analysis` of `files`; reset it whenever they change.
+    pub analysis: OnceCell<deps::Analysis>,
+    pub active_mode: UiMode,
+    pub previous_modes: Vec<UiMode>,
+    pub messages: BTreeMap<CommitId, CommitInfo>,
+    /// The order in which commits are created when saving. Every key of
+    /// `messages` appears here exactly once.
+    pub commit_order: Vec<CommitId>,
+    pub hunk_commits: Vec<Option<CommitId>>,
+    /// Where a visual selection of hunks was started, if any. The selection
+    /// spans from here to the active hunk.
+    pub selection_anchor: Option<usize>,
+}
+
+impl UiState {
+    pub fn push_mode(&mut self, mode: UiMode) {
+        self.previous_modes
+            .push(std::mem::replace(&mut self.active_mode, mode));
+    }
+
+    pub fn set_mode(&mut self, mode: UiMode) -> UiMode {
+        std::mem::replace(&mut self.active_mode, mode)
+    }
+
+    pub fn pop_mode(&mut self) -> Option<UiMode> {
+        let mode = self.previous_modes.pop()?;
+        Some(self.set_mode(mode))
+    }
+
+    pub fn all_hunks_assigned(&self) -> bool {
+        self.hunk_commits.len() >= self.hunk_count()
+            && self.hunk_commits.iter().all(|c| c.is_some())
+    }
+
+    pub fn should_save_commits(&self) -> bool {
+        !self.dont_save && (self.allow_partial || self.all_hunks_assigned())
+    }
+
+    pub fn set_hunk_commit(&mut self, hunk_idx: usize, commit_id: CommitId) {
+        if self.hunk_commits.len() <= hunk_idx {
+            self.hunk_commits
+                .resize_with(hunk_idx + 1, Default::default);
+        }
+        self.hunk_commits[hunk_idx] = Some(commit_id);
+    }
+
+    pub fn unassign_hunk(&mut self, hunk_idx: usize) {
+        if let Some(commit) = self.hunk_commits.get_mut(hunk_idx) {
+            *commit = None;
+        }
+    }
+
+    /// Assigns `hunks` to `commit_id`, first asking for a commit message if
+    /// the commit doesn't exist yet.
+    pub fn assign_hunks(&mut self, hunks: Vec<usize>, commit_id: CommitId) {
+        if self.messages.contains_key(&commit_id) {
+            for hunk_idx in hunks {
+                self.set_hunk_commit(hunk_idx, commit_id);
+            }
+        } else {
+            let mode = UiMode::Editing {
+                commit: commit_id,
+                message: TextEditor::default(),
+                assign_to_hunks: hunks,
+            };
+            self.push_mode(mode);
+        }
+    }
+
+    /// The selected hunks, or just the active one without a selection.
+    pub fn selected_hunks(&self, active_hunk: usize) -> Vec<usize> {
+        match self.selection_anchor {
+            Some(anchor) => (anchor.min(active_hunk)..=anchor.max(active_hunk))
+                .take_while(|idx| *idx < self.hunk_count())
+                .collect(),
+            None => vec![active_hunk],
+        }
+    }
+
+    pub fn unassigned_hunks(&self) -> Vec<usize> {
+        (0..self.hunk_count())
+            .filter(|idx| self.hunk_commits.get(*idx).copied().flatten().is_none())
+            .collect()
+    }
+
+    /// The indices of all hunks in the same file as `hunk_idx`.
+    pub fn file_hunks(&self, hunk_idx: usize) -> Vec<usize> {
+        let Some(((file_id, _), _)) = self.get_hunk(hunk_idx) else {
+            return Vec::new();
+        };
+        self.hunks()
+            .enumerate()
+            .filter(|(_, ((other_file_id, _), _))| *other_file_id == file_id)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    pub fn insert_message(&mut self, commit_id: CommitId, info: CommitInfo) {
+        if self.messages.insert(commit_id, info).is_none() {
+            self.commit_order.push(commit_id);
+        }
+    }
+
+    /// Moves the commit at `idx` in the save order by `offset`, returning its
+    /// new position.
+    pub fn move_commit(&mut self, idx: usize, offset: isize) -> usize {
+        if self.commit_order.is_empty() {
+            return 0;
+        }
+        let new_idx =
+            (idx as isize + offset).clamp(0, self.commit_order.len() as isize - 1) as usize;
+        if idx < self.commit_order.len() {
+            let commit_id = self.commit_order.remove(idx);
+            self.commit_order.insert(new_idx, commit_id);
+        }
+        new_idx
+    }
+
+    /// Commits with at least one hunk assigned, in save order, along with
+    /// their hunk indices.
+    pub fn hunks_by_commit(&self) -> Vec<(CommitId, Vec<usize>)> {
+        let mut hunks_for_commit: BTreeMap<CommitId, Vec<usize>> = self
+            .hunk_commits
+            .iter()
+            .copied()
+            .enumerate()
+            .flat_map(|(hunk_id, commit_id)| Some((commit_id?, hunk_id)))
+            .into_group_map()
+            .into_iter()
+            .collect();
+        self.commit_order
+            .iter()
+            .flat_map(|commit_id| Some((*commit_id, hunks_for_commit.remove(commit_id)?)))
+            .collect()
+    }
+
+    pub fn analysis(&self) -> &deps::Analysis {
+        self.analysis
+            .get_or_init(|| deps::Analysis::new(&self.files))
+    }
+
+    /// The name of the file `hunk_idx` is in.
+    fn hunk_file_name(&self, hunk_idx: usize) -> &str {
+        self.analysis()
+            .file_of_hunk
+            .get(hunk_idx)
+            .map_or("", |file_id| {
+                crate::diff::file_name(&self.files[*file_id].0)
+            })
+    }
+
+    /// Dependencies between hunks that the current assignment breaks: the
+    /// hunk is saved in an earlier commit than the hunk it depends on, or
+    /// that hunk isn't assigned at all.
+    pub fn broken_dependencies(&self) -> Vec<Dependency> {
+        let position = |commit_id: CommitId| self.commit_order.iter().position(|c| *c == commit_id);
+        let commit = |idx: usize| self.hunk_commits.get(idx).copied().flatten();
+        self.analysis()
+            .dependencies
+            .iter()
+            .filter(|dep| match (commit(dep.hunk), commit(dep.on)) {
+                (Some(hunk_commit), Some(on_commit)) => position(hunk_commit) < position(on_commit),
+                (Some(_), None) => true,
+                (None, _) => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the two ends of a moved block would end up in different
+    /// commits, so one of them loses or duplicates the code.
+    pub fn is_split_move(&self, moved: &Move) -> bool {
+        let commit = |idx: usize| self.hunk_commits.get(idx).copied().flatten();
+        commit(moved.from) != commit(moved.to)
+    }
+
+    /// Describes `broken_dependencies` and moved blocks split across commits.
+    pub fn warnings(&self) -> Vec<String> {
+        let moves = self
+            .analysis()
+            .moves
+            .iter()
+            .filter(|moved| self.is_split_move(moved))
+            .map(|moved| self.describe_move(moved));
+        self.broken_dependencies()
+            .into_iter()
+            .map(|dep| self.describe_dependency(&dep))
+            .chain(moves)
+            .collect()
+    }
+
+    pub fn describe_move(&self, moved: &Move) -> String {
+        let commit = |idx: usize| self.hunk_commits.get(idx).copied().flatten().unwrap_or('-');
+        let file = |idx: usize| self.hunk_file_name(idx);
+        format!(
+            "{lines} line(s) moved from hunk {from} ({from_file}) in {from_commit} \
+             to hunk {to} ({to_file}) in {to_commit}",
+            lines = moved.lines,
+            from = moved.from,
+            from_file = file(moved.from),
+            from_commit = commit(moved.from),
+            to = moved.to,
+            to_file = file(moved.to),
+            to_commit = commit(moved.to),
+        )
+    }
+
+    pub fn describe_dependency(&self, dep: &Dependency) -> String {
+        let commit = |idx: usize| self.hunk_commits.get(idx).copied().flatten();
+        let file = |idx: usize| self.hunk_file_name(idx);
+        format!(
+            "{file}: hunk {hunk} in {hunk_commit} {reason} hunk {on} ({on_file}), {on_commit}",
+            file = file(dep.hunk),
+            hunk = dep.hunk,
+            hunk_commit = commit(dep.hunk).unwrap_or('-'),
+            reason = dep.reason,
+            on = dep.on,
+            on_file = file(dep.on),
+            on_commit = match commit(dep.on) {
+                Some(on_commit) => format!("which is saved later in {on_commit}"),
+                None => "which is not assigned".to_string(),
+            },
+        )
+    }
+
+    pub fn hunks(&self) -> impl Iterator<Item = HunkRef<'_>> {
+        self.files
+            .iter()
+            .enumerate()
+            .flat_map(|(file_id, (header, hunks))| {
+                hunks.iter().enumerate().map(move |(hunk_id, hunk)| {
+                    ((file_id, hunk_id), (header.as_str(), hunk.as_str()))
+                })
+            })
+    }
+
+    pub fn get_hunk(&self, idx: usize) -> Option<HunkRef<'_>> {
+        self.hunks().nth(idx)
+    }
+
+    pub fn hunk_count(&self) -> usize {
+        self.files.iter().map(|(_, hunks)| hunks.len()).sum()
+    }
+
+    /// Whether every hunk of `group` already went to its commit.
+    pub fn group_accepted(&self, group: &Group) -> bool {
+        group.commit.is_some()
+            && group
+                .hunks
+                .iter()
+                .all(|idx| self.hunk_commits.get(*idx).copied().flatten() == group.commit)
+    }
+
+    /// Assigns the group's hunks, asking for a message (drafted from the
+    /// group's label) if its commit is new.
+    pub fn accept_group(&mut self, group: &Group) {
+        let Some(commit) = group.commit else {
+            return;
+        };
+        if self.messages.contains_key(&commit) {
+            self.assign_hunks(group.hunks.clone(), commit);
+        } else {
+            let mode = UiMode::Editing {
+                commit,
+                message: TextEditor::new(&group.label),
+                assign_to_hunks: group.hunks.clone(),
+            };
+            self.push_mode(mode);
+        }
+    }
+
+    /// Moves every whitespace-only hunk into the "Formatting" commit,
+    /// creating it with the next free id if needed.
+    pub fn split_formatting(&mut self) {
+        const MESSAGE: &str = "Formatting";
+        let hunks = self
+            .hunks()
+            .enumerate()
+            .filter(|(_, (_, (_, hunk)))| is_whitespace_only(hunk))
+            .map(|(idx, _)| idx)
+            .collect_vec();
+        if hunks.is_empty() {
+            return;
+        }
+        let existing = self
+            .messages
+            .iter()
+            .find(|(_, info)| info.commit_message == MESSAGE)
+            .map(|(id, _)| *id);
+        let Some(commit) =
+            existing.or_else(|| ('1'..='9').find(|id| !self.messages.contains_key(id)))
+        else {
+            return;
+        };
+        let info = CommitInfo {
+            commit_message: MESSAGE.to_string(),
+        };
+        self.insert_message(commit, info);
+        self.assign_hunks(hunks, commit);
+    }
+
+    fn edit_commit_message(&mut self, commit: CommitId) {
+        let message = self
+            .messages
+            .get(&commit)
+            .map(|info| info.commit_message.as_str())
+            .unwrap_or("");
+        let mode = UiMode::Editing {
+            commit,
+            message: TextEditor::new(message),
+            assign_to_hunks: Vec::new(),
+        };
+        self.push_mode(mode);
+    }
+}
+
+use termion::raw::IntoRawMode;
+
+/// One line of `name key` pairs for the mode's bindings, cut off at `width`.
+fn hint_bar(mode: &UiMode, width: usize) -> String {
+    let mut hints = String::new();
+    for binding in keymap::bindings(mode) {
+        let hint = format!("{} {}", binding.keys[0], binding.name);
+        if hints.len() + hint.len() + 2 > width {
+            break;
+        }
+        if !hints.is_empty() {
+            hints.push_str("  ");
+        }
+        hints.push_str(&hint);
+    }
+    hints
+}
+
+/// The diffstat of `commit` followed by its patches, as `git apply` will see
+/// them.
+fn render_preview(commit: &PlannedCommit) -> String {
+    const BAR_WIDTH: usize = 40;
+    let theme = theme();
+    let stat = commit.diffstat();
+    let name_width = stat.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+    let max_changes = stat.iter().map(|(_, a, r)| a + r).max().unwrap_or(0);
+    let mut preview = String::new();
+    writeln!(preview, "{}\n", commit.message.trim_end()).unwrap();
+    for (name, added, removed) in &stat {
+        let scale = |n: usize| match max_changes {
+            0..=BAR_WIDTH => n,
+            max => (n * BAR_WIDTH).div_ceil(max),
+        };
+        writeln!(
+            preview,
+            " {name:name_width$} | {changes:>4} {add}{plus}{del}{minus}{reset}",
+            changes = added + removed,
+            add = theme.paint(theme.added),
+            plus = "+".repeat(scale(*added)),
+            del = theme.paint(theme.removed),
+            minus = "-".repeat(scale(*removed)),
+            reset = theme.reset(),
+        )
+        .unwrap();
+    }
+    let (added, removed) = stat.iter().fold((0, 0), |(a, r), (_, added, removed)| {
+        (a + added, r + removed)
+    });
+    writeln!(
+        preview,
+        " {} file(s) changed, {added} insertion(s)(+), {removed} deletion(s)(-)\n",
+        stat.len()
+    )
+    .unwrap();
+    for patch in &commit.patches {
+        let (header, hunks) = patch.split_at(patch.find("\n@@").map_or(patch.len(), |i| i + 1));
+        write!(
+            preview,
+            "{bold}{header}{reset}{hunks}",
+            bold = theme.paint(Style::BOLD),
+            reset = theme.reset(),
+            hunks = render_hunk(hunks.trim_end_matches('\n'), usize::MAX),
+        )
+        .unwrap();
+    }
+    preview
+}
+
+/// Shows `content` in `$PAGER`, with the UI not reading keys meanwhile.
+fn page(events: &Events, content: impl std::fmt::Display) {
+    let pager = std::env::var("PAGER").ok();
+    let result = events.suspend(|| {
+        spawn_with_input(&mut sh(pager.as_deref().unwrap_or("less")), |stdin| {
+            write!(stdin, "{content}")?;
+            Ok(())
+        })?
+        .wait()
+        .map_err(anyhow::Error::from)
+    });
+    if let Err(err) = result {
+        log::error!("Failed to run the pager {err:?}");
+    }
+}
+
+/// Runs the UI until the user quits or `save` has created the commits they
+/// asked for. `save` runs on a background thread, reporting progress to the
+/// UI. If it fails, it must leave things as they were, so that the user can
+/// go back to the hunks and save again.
+pub fn run(
+    mut ui_state: UiState,
+    save: impl Fn(Vec<PlannedCommit>, &CommitOptions, &Progress) -> Result<()> + Send + Sync + 'static,
+) -> Result<UiState> {
+    // E.g. a diff that only changes file modes.
+    anyhow::ensure!(ui_state.hunk_count() > 0, "There are no hunks to split");
+    let theme = theme();
+    let mut commit_colors_seq = theme
+        .commits
+        .iter()
+        .map(|style| theme.paint(*style).to_string());
+    let mut commit_colors: BTreeMap<CommitId, Option<String>> = BTreeMap::new();
+    let events = Events::new()?;
+    let save = Arc::new(save);
+    let stdout = stdout();
+    let stdout = stdout.into_raw_mode()?;
+    let mut screen = Screen::new(termion::screen::AlternateScreen::from(stdout));
+    // let mut screen = stdout;
+    let mut draw_buffer = String::new();
+    ui_state.dirty = true;
+    'ui_loop: loop {
+        let terminal_size = termion::terminal_size().unwrap_or_default();
+        if terminal_size != ui_state.terminal_size {
+            ui_state.terminal_size = terminal_size;
+            ui_state.dirty = true;
+        }
+        if ui_state.dirty || ui_state.force_redraw {
+            if ui_state.force_redraw {
+                screen.invalidate();
+            }
+            let terminal_height = ui_state.terminal_size.1;
+            let warnings = ui_state.warnings();
+            let active_commit = match ui_state.active_mode {
+                UiMode::CommitList { active_commit } => Some(active_commit),
+                _ => None,
+            };
+            let flag = |on: bool, name: &'static str| {
+                on.then_some(FmtFn(move |f| {
+                    write!(
+                        f,
+                        " {color}{name}{reset}",
+                        color = theme.paint(theme.warning),
+                        reset = theme.reset(),
+                    )
+                }))
+                .into_or_display("")
+            };
+            writeln!(
+                draw_buffer,
+                "{x}/{n} hunks assigned{partial}{no_verify}{failed}",
+                x = ui_state.hunk_commits.iter().filter(|h| h.is_some()).count(),
+                n = ui_state.hunk_count(),
+                partial = flag(ui_state.allow_partial, "ALLOW PARTIAL"),
+                no_verify = flag(ui_state.commit_options.no_verify, "NO VERIFY"),
+                failed = flag(ui_state.save_error.is_some(), "SAVING FAILED"),
+            )?;
+            // The messages as they will be saved, for commits with hunks.
+            let planned = save::plan(&ui_state)
+                .into_iter()
+                .map(|commit| (commit.id, commit.message))
+                .collect::<BTreeMap<_, _>>();
+            for (idx, id) in ui_state.commit_order.iter().enumerate() {
+                let commit_message = planned
+                    .get(id)
+                    .unwrap_or(&ui_state.messages[id].commit_message);
+                writeln!(
+                    draw_buffer,
+                    "{marker}{color}{id}: {commit_message}{reset}",
+                    marker = match active_commit {
+                        Some(active_commit) if active_commit == idx => "> ",
+                        Some(_) => "  ",
+                        None => "",
+                    },
+                    commit_message = commit_message.split('\n').next().unwrap(),
+                    color = commit_colors
+                        .entry(*id)
+                        .or_insert_with(|| commit_colors_seq.next())
+                        .or_display(""),
+                    reset = theme.reset(),
+                )?;
+            }
+            let warning_lines = if active_commit.is_some() {
+                for warning in &warnings {
+                    writeln!(
+                        draw_buffer,
+                        "{color}warning: {warning}{reset}",
+                        color = theme.paint(theme.warning),
+                        reset = theme.reset(),
+                    )?;
+                }
+                warnings.len()
+            } else if !warnings.is_empty() {
+                writeln!(
+                    draw_buffer,
+                    "{color}{n} warning(s), press Tab to view{reset}",
+                    n = warnings.len(),
+                    color = theme.paint(theme.warning),
+                    reset = theme.reset(),
+                )?;
+                1
+            } else {
+                0
+            };
+            match &ui_state.active_mode {
+                UiMode::WaitingToEdit => {
+                    write!(draw_buffer, "Enter commit id to edit: ",)?;
+                }
+                UiMode::WaitingToAssign { what, .. } => {
+                    write!(draw_buffer, "Enter commit id to assign {what} to: ")?;
+                }
+                UiMode::CommitList { .. } => {}
+                UiMode::Suggesting {
+                    strategy,
+                    groups,
+                    active_group,
+                } => {
+                    writeln!(draw_buffer, "Suggested groups by {}:", strategy.name())?;
+                    for (idx, group) in groups.iter().enumerate() {
+                        let files = group
+                            .hunks
+                            .iter()
+                            .filter_map(|idx| ui_state.get_hunk(*idx))
+                            .map(|(_, (header, _))| crate::diff::file_name(header))
+                            .dedup()
+                            .join(", ");
+                        writeln!(
+                            draw_buffer,
+                            "{marker}{color}{id}?{reset} {label} ({n} hunk(s){accepted}): {files}",
+                            marker = if idx == *active_group { "> " } else { "  " },
+                            id = group.commit.unwrap_or('-'),
+                            color = group
+                                .commit
+                                .and_then(|commit| commit_colors
+                                    .entry(commit)
+                                    .or_insert_with(|| commit_colors_seq.next())
+                                    .as_ref())
+                                .into_or_display(""),
+                            reset = theme.reset(),
+                            label = group.label,
+                            n = group.hunks.len(),
+                            accepted = if ui_state.group_accepted(group) {
+                                ", accepted"
+                            } else if group.commit.is_none() {
+                                ", no free commit id"
+                            } else {
+                                ""
+                            },
+                        )?;
+                    }
+                }
+                UiMode::Saving { log, error } => {
+                    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+                    writeln!(
+                        draw_buffer,
+                        "Saving commits {}",
+                        if error.is_some() {
+                            ' '
+                        } else {
+                            SPINNER[ui_state.ticks % SPINNER.len()]
+                        }
+                    )?;
+                    for line in log {
+                        match line.starts_with("warning: ") {
+                            true => writeln!(
+                                draw_buffer,
+                                "{color}{line}{reset}",
+                                color = theme.paint(theme.warning),
+                                reset = theme.reset(),
+                            )?,
+                            false => writeln!(draw_buffer, "{line}")?,
+                        }
+                    }
+                    if let Some(error) = error {
+                        writeln!(
+                            draw_buffer,
+                            "{color}{error}{reset}",
+                            color = theme.paint(theme.error),
+                            reset = theme.reset(),
+                        )?;
+                    }
+                }
+                UiMode::Preview { commit, scroll } => {
+                    // The preview takes the whole screen.
+                    let (commit, scroll) = (*commit, *scroll);
+                    draw_buffer.clear();
+                    let preview = save::plan(&ui_state)
+                        .into_iter()
+                        .find(|c| c.id == commit)
+                        .map(|planned| render_preview(&planned))
+                        .unwrap_or_else(|| format!("No hunks are assigned to {commit}\n"));
+                    let lines = preview.split('\n').collect_vec();
+                    let scroll = scroll.min(lines.len().saturating_sub(1));
+                    ui_state.active_mode = UiMode::Preview { commit, scroll };
+                    draw_buffer.push_str(&lines[scroll..].join("\n"));
+                }
+                UiMode::Help => {
+                    let described_mode = ui_state
+                        .previous_modes
+                        .last()
+                        .unwrap_or(&ui_state.active_mode);
+                    writeln!(draw_buffer, "Keys ({}):", described_mode.name())?;
+                    for binding in keymap::bindings(described_mode) {
+                        writeln!(
+                            draw_buffer,
+                            "  {keys:<16} {description}",
+                            keys = binding.keys.iter().join(", "),
+                            description = binding.description,
+                        )?;
+                    }
+                }
+                UiMode::Editing {
+                    commit,
+                    message,
+                    assign_to_hunks: _,
+                } => {
+                    write!(
+                        draw_buffer,
+                        "For {color}{commit}{reset} (subject {len}/{SUBJECT_WIDTH}):\n{message}",
+                        len = message.subject().chars().count(),
+                        message = message.render(),
+                        color = commit_colors
+                            .entry(*commit)
+                            .or_insert_with(|| commit_colors_seq.next())
+                            .or_display(""),
+                        reset = theme.reset(),
+                    )?;
+                    if let Some(lint) = message.lint() {
+                        writeln!(
+                            draw_buffer,
+                            "{color}{lint}{reset}",
+                            color = theme.paint(theme.warning),
+                            reset = theme.reset(),
+                        )?;
+                    }
+                }
+                UiMode::Viewing { active_hunk } => {
+                    let ((_file_id, _hunk_id), (header, hunk)) =
+                        ui_state.get_hunk(*active_hunk).unwrap();
+                    let commit = ui_state.hunk_commits.get(*active_hunk).copied().flatten();
+                    let commit_message = commit
+                        .map(|commit| {
+                            let messages = &ui_state.messages;
+                            FmtFn(move |f| {
+                                write!(
+                                    f,
+                                    "[{commit}] {}",
+                                    messages[&commit].commit_message.split('\n').next().unwrap()
+                                )
+                            })
+                        })
+                        .into_or_display("---");
+                    let header_line = header.split('\n').next().unwrap();
+                    let whitespace_only = is_whitespace_only(hunk).then_some(FmtFn(|f| {
+                        write!(
+                            f,
+                            " {color}[whitespace only]{reset}",
+                            color = theme.paint(theme.faint),
+                            reset = theme.reset(),
+                        )
+                    }));
+                    let selection = ui_state.selection_anchor.map(|anchor| {
+                        FmtFn(move |f| {
+                            write!(
+                                f,
+                                " {color}[selected {start}-{end}]{reset}",
+                                start = anchor.min(*active_hunk),
+                                end = anchor.max(*active_hunk),
+                                color = theme.paint(Style::REVERSE),
+                                reset = theme.reset(),
+                            )
+                        })
+                    });
+                    writeln!(
+                        draw_buffer,
+                        "{active_hunk}/{n}{selection}{whitespace_only}: {color}{commit_message}{reset}\n{header_line}",
+                        n = ui_state.hunk_count(),
+                        selection = selection.into_or_display(""),
+                        whitespace_only = whitespace_only.or_display(""),
+                        color = commit
+                            .and_then(|commit| commit_colors
+                                .entry(commit)
+                                .or_insert_with(|| commit_colors_seq.next())
+                                .as_ref())
+                            .into_or_display(""),
+                        reset = theme.reset(),
+                    )?;
+                    let risky = ui_state
+                        .broken_dependencies()
+                        .into_iter()
+                        .filter(|dep| dep.hunk == *active_hunk || dep.on == *active_hunk)
+                        .collect_vec();
+                    for dep in &risky {
+                        writeln!(
+                            draw_buffer,
+                            "{color}risky: {}{reset}",
+                            ui_state.describe_dependency(dep),
+                            color = theme.paint(theme.warning),
+                            reset = theme.reset(),
+                        )?;
+                    }
+                    let moves = ui_state
+                        .analysis()
+                        .moves
+                        .iter()
+                        .filter(|moved| moved.from == *active_hunk || moved.to == *active_hunk)
+                        .collect_vec();
+                    for moved in &moves {
+                        writeln!(
+                            draw_buffer,
+                            "{color}moved: {}{reset}",
+                            ui_state.describe_move(moved),
+                            color = theme.paint(if ui_state.is_split_move(moved) {
+                                theme.warning
+                            } else {
+                                theme.faint
+                            }),
+                            reset = theme.reset(),
+                        )?;
+                    }
+                    let max_lines = (terminal_height as usize).saturating_sub(
+                        ui_state.messages.len() + warning_lines + risky.len() + moves.len() + 6,
+                    );
+                    let (width, _) = ui_state.terminal_size;
+                    if ui_state.side_by_side && width >= SIDE_BY_SIDE_MIN_WIDTH {
+                        writeln!(
+                            draw_buffer,
+                            "{}",
+                            render_hunk_side_by_side(hunk, width as usize, max_lines)
+                        )?;
+                    } else {
+                        writeln!(draw_buffer, "{}", render_hunk(hunk, max_lines))?;
+                    }
+                }
+            }
+            let hints = format!(
+                "{invert}{hints}{reset}",
+                invert = theme.paint(Style::REVERSE),
+                hints = hint_bar(&ui_state.active_mode, ui_state.terminal_size.0 as usize),
+                reset = theme.reset(),
+            );
+            screen.draw(ui_state.terminal_size, &draw_buffer, &hints)?;
+            draw_buffer.clear();
+            ui_state.dirty = false;
+            ui_state.force_redraw = false;
+        }
+        let key = match events.next() {
+            Event::Key(key) => key,
+            Event::InputClosed if !matches!(ui_state.active_mode, UiMode::Saving { .. }) => {
+                break 'ui_loop;
+            }
+            Event::Tick if matches!(ui_state.active_mode, UiMode::Saving { error: None, .. }) => {
+                ui_state.ticks += 1;
+                ui_state.dirty = true;
+                continue;
+            }
+            Event::Progress(line) => {
+                if let UiMode::Saving { log, .. } = &mut ui_state.active_mode {
+                    log.push(line);
+                    ui_state.dirty = true;
+                }
+                continue;
+            }
+            Event::TaskDone(Ok(())) => {
+                ui_state.saved = true;
+                break 'ui_loop;
+            }
+            Event::TaskDone(Err(err)) => {
+                if let UiMode::Saving { error, .. } = &mut ui_state.active_mode {
+                    *error = Some(err);
+                    ui_state.dirty = true;
+                }
+                continue;
+            }
+            // The terminal size is checked on every iteration.
+            Event::Resize | Event::Tick | Event::InputClosed => continue,
+        };
+        let action = if let Some(action) = keymap::lookup(&ui_state.active_mode, key) {
+            action
+        } else {
+            continue;
+        };
+        ui_state.dirty = true;
+        match &ui_state.active_mode {
+            UiMode::WaitingToEdit => {
+                ui_state.pop_mode();
+                if let (Action::PickCommit, termion::event::Key::Char(commit)) = (action, key) {
+                    ui_state.edit_commit_message(commit);
+                }
+            }
+            UiMode::WaitingToAssign { hunks, .. } => {
+                let hunks = hunks.clone();
+                ui_state.pop_mode();
+                if let (Action::PickCommit, termion::event::Key::Char(commit)) = (action, key) {
+                    ui_state.assign_hunks(hunks, commit);
+                }
+            }
+            UiMode::Help => {
+                ui_state.pop_mode();
+            }
+            UiMode::Saving { error, .. } => {
+                if let Some(error) = error {
+                    // Kept for the caller in case the user gives up.
+                    ui_state.save_error = Some(error.clone());
+                    ui_state.pop_mode();
+                }
+            }
+            UiMode::CommitList { active_commit } => {
+                let active_commit = *active_commit;
+                let last = ui_state.commit_order.len().saturating_sub(1);
+                match action {
+                    Action::SelectPrev => {
+                        let mode = UiMode::CommitList {
+                            active_commit: active_commit.saturating_sub(1),
+                        };
+                        ui_state.set_mode(mode);
+                    }
+                    Action::SelectNext => {
+                        let mode = UiMode::CommitList {
+                            active_commit: (active_commit + 1).min(last),
+                        };
+                        ui_state.set_mode(mode);
+                    }
+                    Action::MoveCommitUp => {
+                        let active_commit = ui_state.move_commit(active_commit, -1);
+                        ui_state.set_mode(UiMode::CommitList { active_commit });
+                    }
+                    Action::MoveCommitDown => {
+                        let active_commit = ui_state.move_commit(active_commit, 1);
+                        ui_state.set_mode(UiMode::CommitList { active_commit });
+                    }
+                    Action::EditSelected => {
+                        if let Some(&commit) = ui_state.commit_order.get(active_commit) {
+                            ui_state.edit_commit_message(commit);
+                        }
+                    }
+                    Action::PreviewCommit => {
+                        if let Some(&commit) = ui_state.commit_order.get(active_commit) {
+                            ui_state.push_mode(UiMode::Preview { commit, scroll: 0 });
+                        }
+                    }
+                    Action::Back => {
+                        ui_state.pop_mode();
+                    }
+                    Action::Help => {
+                        ui_state.push_mode(UiMode::Help);
+                    }
+                    _ => (),
+                }
+            }
+            UiMode::Suggesting {
+                strategy,
+                groups,
+                active_group,
+            } => {
+                let (strategy, active_group) = (*strategy, *active_group);
+                let last = groups.len().saturating_sub(1);
+                match action {
+                    Action::SelectPrev | Action::SelectNext => {
+                        let active_group = match action {
+                            Action::SelectPrev => active_group.saturating_sub(1),
+                            _ => (active_group + 1).min(last),
+                        };
+                        if let UiMode::Suggesting {
+                            active_group: active,
+                            ..
+                        } = &mut ui_state.active_mode
+                        {
+                            *active = active_group;
+                        }
+                    }
+                    Action::NextStrategy => {
+                        let strategy = strategy.next();
+                        let mode = UiMode::Suggesting {
+                            strategy,
+                            groups: suggest::suggest(&ui_state, strategy),
+                            active_group: 0,
+                        };
+                        ui_state.set_mode(mode);
+                    }
+                    Action::AcceptGroup => {
+                        if let Some(group) = groups.get(active_group).cloned() {
+                            ui_state.accept_group(&group);
+                        }
+                    }
+                    Action::AcceptAll => {
+                        for group in groups.clone() {
+                            let Some(commit) = group.commit else {
+                                continue;
+                            };
+                            if !ui_state.messages.contains_key(&commit) {
+                                let info = CommitInfo {
+                                    commit_message: group.label.clone(),
+                                };
+                                ui_state.insert_message(commit, info);
+                            }
+                            ui_state.assign_hunks(group.hunks, commit);
+                        }
+                        ui_state.pop_mode();
+                    }
+                    Action::Help => {
+                        ui_state.push_mode(UiMode::Help);
+                    }
+                    Action::Back => {
+                        ui_state.pop_mode();
+                    }
+                    _ => (),
+                }
+            }
+            UiMode::Preview { commit, scroll } => {
+                let (commit, scroll) = (*commit, *scroll);
+                let page_height = (ui_state.terminal_size.1 as usize).saturating_sub(2);
+                let scroll = match action {
+                    Action::ScrollUp => scroll.saturating_sub(1),
+                    Action::ScrollDown => scroll + 1,
+                    Action::PageUp => scroll.saturating_sub(page_height),
+                    Action::PageDown => scroll + page_height,
+                    Action::Pager => {
+                        if let Some(planned) =
+                            save::plan(&ui_state).into_iter().find(|c| c.id == commit)
+                        {
+                            page(&events, render_preview(&planned));
+                        }
+                        ui_state.force_redraw = true;
+                        scroll
+                    }
+                    Action::Help => {
+                        ui_state.push_mode(UiMode::Help);
+                        continue;
+                    }
+                    Action::Back => {
+                        ui_state.pop_mode();
+                        continue;
+                    }
+                    _ => scroll,
+                };
+                ui_state.set_mode(UiMode::Preview { commit, scroll });
+            }
+            UiMode::Editing { message, .. } => match action {
+                Action::Back => {
+                    ui_state.pop_mode();
+                }
+                Action::Confirm if !message.is_empty() => match ui_state.pop_mode() {
+                    Some(UiMode::Editing {
+                        commit,
+                        message,
+                        assign_to_hunks,
+                    }) => {
+                        ui_state.insert_message(
+                            commit,
+                            CommitInfo {
+                                commit_message: message.text(),
+                            },
+                        );
+                        for hunk_idx in assign_to_hunks {
+                            ui_state.set_hunk_commit(hunk_idx, commit);
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                Action::ExternalEditor => {
+                    let new_message = events.suspend(|| edit(message.text()));
+                    if let (UiMode::Editing { message, .. }, Ok(new_message)) =
+                        (&mut ui_state.active_mode, new_message)
+                    {
+                        *message = TextEditor::new(&new_message);
+                    }
+                    ui_state.force_redraw = true;
+                }
+                action => {
+                    let UiMode::Editing { message, .. } = &mut ui_state.active_mode else {
+                        unreachable!()
+                    };
+                    match (action, key) {
+                        (Action::InsertChar, termion::event::Key::Char(c)) => {
+                            message.insert_char(c)
+                        }
+                        (Action::Newline, _) => message.insert_newline(),
+                        (Action::DeleteChar, _) => message.backspace(),
+                        (Action::DeleteForward, _) => message.delete(),
+                        (Action::DeleteWord, _) => message.delete_word(),
+                        (Action::CursorLeft, _) => message.left(),
+                        (Action::CursorRight, _) => message.right(),
+                        (Action::CursorUp, _) => message.up(),
+                        (Action::CursorDown, _) => message.down(),
+                        (Action::LineStart, _) => message.home(),
+                        (Action::LineEnd, _) => message.end(),
+                        _ => (),
+                    }
+                }
+            },
+            UiMode::Viewing { active_hunk } => {
+                let active_hunk = *active_hunk;
+                match action {
+                    Action::TogglePartial => {
+                        ui_state.allow_partial = !ui_state.allow_partial;
+                    }
+                    Action::ToggleNoVerify => {
+                        ui_state.commit_options.no_verify = !ui_state.commit_options.no_verify;
+                    }
+                    Action::Redraw => {
+                        ui_state.force_redraw = true;
+                    }
+                    Action::ToggleSideBySide => {
+                        ui_state.side_by_side = !ui_state.side_by_side;
+                    }
+                    // https://github.com/twaugh/patchutils/blob/master/src/rediff.c
+                    Action::EditHunk => {
+                        let launched_editor = loop {
+                            let ((file_id, _), (_, hunk_body)) =
+                                ui_state.get_hunk(active_hunk).unwrap();
+                            match events.suspend(|| edit(hunk_body)) {
+                                Ok(output) if output.trim().is_empty() => break true,
+                                Ok(output) => {
+                                    ui_state.files[file_id].1.push(output);
+                                }
+                                Err(err) => {
+                                    log::error!("Failed to edit hunk body {err:?}");
+                                    break false;
+                                }
+                            }
+                        };
+                        ui_state.analysis.take();
+                        if launched_editor {
+                            // Works because we're always appending the
+                            // hunks after the original, so the hunk id
+                            // is preserved up to active_hunk.
+                            let ((file_id, hunk_id), _) = ui_state.get_hunk(active_hunk).unwrap();
+
+                            ui_state.files[file_id].1.remove(hunk_id);
+                            ui_state.hunk_commits.truncate(ui_state.hunk_count());
+                            // The hunks moved under the selection.
+                            ui_state.selection_anchor = None;
+                            let mode = UiMode::Viewing {
+                                active_hunk: active_hunk
+                                    .min(ui_state.hunk_count().saturating_sub(1)),
+                            };
+                            ui_state.set_mode(mode);
+                        }
+                    }
+                    Action::Pager => {
+                        let (_, (header, hunk_body)) = ui_state.get_hunk(active_hunk).unwrap();
+                        page(
+                            &events,
+                            FmtFn(|f| {
+                                write!(f, "{header}\n{}", render_hunk(hunk_body, usize::MAX))
+                            }),
+                        );
+                        ui_state.force_redraw = true;
+                    }
+                    // TODO allow splitting a hunk?
+                    Action::Save if ui_state.should_save_commits() => {
+                        let warnings = ui_state
+                            .warnings()
+                            .into_iter()
+                            .map(|warning| {
+                                log::warn!("{warning}");
+                                format!("warning: {warning}")
+                            })
+                            .collect();
+                        let plan = save::plan(&ui_state);
+                        let save = save.clone();
+                        let commit_options = ui_state.commit_options.clone();
+                        let progress = events.progress();
+                        std::thread::spawn(move || {
+                            let result = save(plan, &commit_options, &progress);
+                            progress.finish(result);
+                        });
+                        ui_state.save_error = None;
+                        let mode = UiMode::Saving {
+                            log: warnings,
+                            error: None,
+                        };
+                        ui_state.push_mode(mode);
+                    }
+                    Action::Quit => {
+                        ui_state.dont_save = true;
+                        break 'ui_loop;
+                    }
+                    Action::ShowCommits => {
+                        ui_state.push_mode(UiMode::CommitList { active_commit: 0 });
+                    }
+                    Action::SplitFormatting => {
+                        ui_state.split_formatting();
+                    }
+                    Action::Suggest => {
+                        let strategy = Strategy::Directory;
+                        let mode = UiMode::Suggesting {
+                            strategy,
+                            groups: suggest::suggest(&ui_state, strategy),
+                            active_group: 0,
+                        };
+                        ui_state.push_mode(mode);
+                    }
+                    Action::Help => {
+                        ui_state.push_mode(UiMode::Help);
+                    }
+                    Action::AssignCommit => {
+                        let termion::event::Key::Char(c) = key else {
+                            unreachable!()
+                        };
+                        let hunks = ui_state.selected_hunks(active_hunk);
+                        ui_state.selection_anchor = None;
+                        ui_state.assign_hunks(hunks, c);
+                    }
+                    Action::Unassign => {
+                        for hunk_idx in ui_state.selected_hunks(active_hunk) {
+                            ui_state.unassign_hunk(hunk_idx);
+                        }
+                        ui_state.selection_anchor = None;
+                    }
+                    Action::ToggleSelection => {
+                        ui_state.selection_anchor = match ui_state.selection_anchor {
+                            Some(_) => None,
+                            None => Some(active_hunk),
+                        };
+                    }
+                    Action::ClearSelection => {
+                        ui_state.selection_anchor = None;
+                    }
+                    Action::AssignUnassigned => {
+                        let hunks = ui_state.unassigned_hunks();
+                        if !hunks.is_empty() {
+                            let mode = UiMode::WaitingToAssign {
+                                what: format!("{} unassigned hunk(s)", hunks.len()),
+                                hunks,
+                            };
+                            ui_state.push_mode(mode);
+                        }
+                    }
+                    Action::AssignFile => {
+                        let hunks = ui_state.file_hunks(active_hunk);
+                        let mode = UiMode::WaitingToAssign {
+                            what: format!("all {} hunk(s) in this file", hunks.len()),
+                            hunks,
+                        };
+                        ui_state.push_mode(mode);
+                    }
+                    Action::EditMessage => {
+                        ui_state.push_mode(UiMode::WaitingToEdit);
+                    }
+                    Action::PrevHunk => {
+                        let mode = UiMode::Viewing {
+                            active_hunk: active_hunk.saturating_sub(1),
+                        };
+                        ui_state.set_mode(mode);
+                    }
+                    Action::NextHunk if (active_hunk + 1) < ui_state.hunk_count() => {
+                        let mode = UiMode::Viewing {
+                            active_hunk: active_hunk + 1,
+                        };
+                        ui_state.set_mode(mode);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    } // 'ui_loop
+    Ok(ui_state)
+}
@@ -0,0 +1,105 @@
+//! Splitting changes that aren't committed yet, from the working tree or a
+//! stash entry, into new commits on top of HEAD, without a rebase.
+
+use crate::backend::{self, ApplyTo, CommitOptions};
+use crate::diff::parse_diff;
+use crate::save::{self, MessageFormat};
+use crate::ui::{self, UiState};
+use crate::{get_output, git, rev_parse};
+use anyhow::Result;
+
+pub fn split_worktree(
+    diff_context_size: usize,
+    commit_options: &CommitOptions,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let raw_hunks =
+        backend::open(diff_context_size, ApplyTo::Index, &Default::default()).diff("HEAD", None)?;
+    let files = parse_diff(&raw_hunks)?;
+    anyhow::ensure!(
+        files.iter().any(|(_, hunks)| !hunks.is_empty()),
+        "There are no uncommitted changes to split"
+    );
+    let ui_state = UiState {
+        hunk_commits: vec![None; files.iter().map(|(_, hunks)| hunks.len()).sum()],
+        files,
+        // Whatever isn't assigned stays uncommitted.
+        allow_partial: true,
+        message_format,
+        commit_options: commit_options.clone(),
+        ..Default::default()
+    };
+    let ui_state = ui::run(ui_state, move |plan, commit_options, progress| {
+        // The changes are all in the working tree already, so build each
+        // commit in the index only.
+        let added = get_output(git().args(["diff", "--cached", "--name-only", "--diff-filter=A"]))?;
+        get_output(git().args(["reset", "-q"]))?;
+        let saved = save::save(
+            &plan,
+            &*backend::open(diff_context_size, ApplyTo::Index, commit_options),
+            &Default::default(),
+            progress,
+        );
+        // Don't let new files that stay uncommitted drop out of git's sight,
+        // even if not all commits could be created.
+        for file in added.lines() {
+            get_output(git().args(["add", "--", file]))?;
+        }
+        saved
+    })?;
+    if let Some(err) = ui_state.save_error {
+        anyhow::bail!("Failed to save the commits:\n{err}");
+    }
+    Ok(())
+}
+
+/// Splits the changes of `stash` (relative to the commit it was made on)
+/// into commits on HEAD, dropping the stash once HEAD has its tree.
+pub fn split_stash(
+    stash: &str,
+    diff_context_size: usize,
+    commit_options: &CommitOptions,
+    message_format: MessageFormat,
+) -> Result<()> {
+    anyhow::ensure!(
+        git().args(["diff", "--quiet", "HEAD"]).status()?.success(),
+        "Splitting a stash needs a clean working tree"
+    );
+    let stash_commit = rev_parse(stash)?;
+    let raw_hunks = backend::open(diff_context_size, ApplyTo::WorkTree, &Default::default())
+        .diff(&format!("{stash_commit}^1"), Some(&stash_commit))?;
+    let files = parse_diff(&raw_hunks)?;
+    anyhow::ensure!(
+        files.iter().any(|(_, hunks)| !hunks.is_empty()),
+        "{stash} has no changes to split"
+    );
+    let ui_state = UiState {
+        hunk_commits: vec![None; files.iter().map(|(_, hunks)| hunks.len()).sum()],
+        files,
+        message_format,
+        source_commit: Some(stash_commit.clone()),
+        commit_options: commit_options.clone(),
+        ..Default::default()
+    };
+    let ui_state = ui::run(ui_state, move |plan, commit_options, progress| {
+        save::save(
+            &plan,
+            &*backend::open(diff_context_size, ApplyTo::WorkTree, commit_options),
+            &Default::default(),
+            progress,
+        )
+    })?;
+    if let Some(err) = ui_state.save_error {
+        anyhow::bail!("Failed to save the commits:\n{err}");
+    }
+    if !ui_state.saved {
+        return Ok(());
+    }
+    if rev_parse("HEAD^{tree}")? == rev_parse(format!("{stash_commit}^{{tree}}"))? {
+        get_output(git().args(["stash", "drop", "-q", stash]))?;
+    } else {
+        log::warn!("HEAD doesn't match {stash} (not all hunks were committed?), keeping it");
+        eprintln!("Kept {stash}: the new commits don't add up to its tree");
+    }
+    Ok(())
+}